@@ -1,26 +1,134 @@
 // Jackson Coxson
 
 use base64::Engine;
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
-use flate2::read::GzDecoder;
-use reqwest::Client;
-use std::collections::HashMap;
-use std::io::Read;
-use tokio::fs::{self, File};
-use tokio::io::{self, AsyncWriteExt};
-
+use chrono::Utc;
+use chrono_tz::Tz;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use reqwest::{Client, RequestBuilder};
+use std::time::Duration;
+
+use crate::cache::{Cache, CacheMeta, Resource};
+use crate::cassette::CassetteMode;
+use crate::encoding::decode_body;
+use crate::error::{parse_json, ApiError};
 use crate::info::MissionaryInfo;
-use crate::timeline::{CommandsResponse, Person, TimelineEntry, TimelineEventKind};
+use crate::profile::DeviceProfile;
+use crate::retry::{RateLimiter, RetryPolicy};
+use crate::snapshot::SnapshotStore;
+use crate::sync::SyncState;
+use crate::timeline::{CommandsResponse, Person};
+
+/// Where the Areabook app talks to in production.
+pub const DEFAULT_BASE_URL: &str = "https://missionary.churchofjesuschrist.org/ws/areabook/v5.2";
+/// Gentle enough that a full mission sync doesn't stand out.
+pub const DEFAULT_REQUESTS_PER_SECOND: f64 = 2.0;
+/// A big area's commands can take a while, but not this long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
 
 pub struct MissionaryApi {
     username: String,
     password: String,
+    base_url: String,
+    profile: DeviceProfile,
+    /// Shared so every request reuses the same connection pool
+    client: Client,
+    retry: RetryPolicy,
+    rate_limiter: RateLimiter,
+    cache: Cache,
+    history: SnapshotStore,
+    /// Records or replays every exchange instead of only talking to the server
+    cassette: Option<CassetteMode>,
+    /// The mission's timezone, for the dates Areabook sends without one
+    timezone: Tz,
 }
 
 impl MissionaryApi {
     /// Creates a new instance of the API client.
     pub fn new(username: String, password: String) -> Self {
-        Self { username, password }
+        Self {
+            username,
+            password,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            profile: DeviceProfile::default(),
+            client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build HTTP client"),
+            retry: RetryPolicy::default(),
+            rate_limiter: RateLimiter::new(DEFAULT_REQUESTS_PER_SECOND),
+            cache: Cache::new("cache"),
+            history: SnapshotStore::new("history"),
+            cassette: None,
+            timezone: Tz::UTC,
+        }
+    }
+
+    /// Keeps a snapshot of every area sync somewhere other than `history/`.
+    pub fn with_history(mut self, history: SnapshotStore) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Keeps everything fetched somewhere other than `cache/`.
+    pub fn with_cache(mut self, cache: Cache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Records every exchange to a cassette, or replays one without touching
+    /// the network.
+    pub fn with_cassette(mut self, cassette: CassetteMode) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Whether responses come from a cassette instead of the server.
+    pub fn is_replay(&self) -> bool {
+        self.cassette.as_ref().is_some_and(CassetteMode::is_replay)
+    }
+
+    /// Reads bare dates as days in `timezone` instead of UTC.
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// Changes how failed requests are retried.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Caps how many requests per second go out, across all areas.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.rate_limiter = RateLimiter::new(requests_per_second);
+        self
+    }
+
+    /// Uses a different device profile for every request's headers.
+    pub fn with_profile(mut self, profile: DeviceProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Points the client at a different server, such as the `mock-server` binary.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Joins an endpoint path onto the base URL.
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
     }
 
     /// Builds the Base64-encoded authorization header.
@@ -33,245 +141,337 @@ impl MissionaryApi {
         format!("Basic {}", CUSTOM_ENGINE.encode(credentials))
     }
 
-    /// Fetches user details and saves the result as `info.json`.
-    pub async fn fetch_user_details(
-        &self,
-        output_path: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let url = "https://missionary.churchofjesuschrist.org/ws/areabook/v5.2/user/details";
-
-        let client = Client::new();
-        let response = client
-            .get(url)
+    /// Starts a GET request carrying the headers the app sends on every call.
+    fn request(&self, path: &str) -> RequestBuilder {
+        let profile = &self.profile;
+        self.client
+            .get(self.url(path))
             .header("Accept-Encoding", "gzip")
-            .header("Accept-Language", "en-US")
+            .header("Accept-Language", &profile.language)
             .header("Authorization", self.get_auth_header())
-            .header("client-version", "6.20.0")
+            .header("client-version", &profile.client_version)
             .header("Connection", "Keep-Alive")
-            .header("device-type", "Android")
+            .header("device-type", &profile.device_type)
             .header("forceNoDataGuard", "false")
             .header("forceUseDataGuard", "false")
-            .header("group-name", "SRG")
-            .header("Host", "missionary.churchofjesuschrist.org")
+            .header("group-name", &profile.group_name)
+            .header("User-Agent", profile.user_agent())
+    }
+
+    /// Fetches user details and saves the result in the cache.
+    pub async fn fetch_user_details(&self) -> Result<(), ApiError> {
+        let request = self
+            .request("user/details")
             .header("lastSync", "")
-            .header("skipMtcMentorAccess", "false")
-            .header(
-                "User-Agent",
-                "Area Book 6.20.0 (620037). Android Android SDK built for x86_64. Android 14 (api 34) 81c9cf2ff6e4bdee",
-            )
-            .send()
-            .await?
-            .bytes()
-            .await?;
+            .header("skipMtcMentorAccess", "false");
+        let data = self.download(request).await?;
+        self.cache
+            .write(&Resource::UserDetails, &data, &self.meta("user/details"))
+            .await
+    }
 
-        let gz_path = format!("{}.gz", output_path);
-        let mut gz_file = File::create(&gz_path).await?;
-        gz_file.write_all(&response).await?;
+    /// Describes a response from `path` for the cache.
+    fn meta(&self, path: &str) -> CacheMeta {
+        CacheMeta::new(&self.profile.client_version, &self.url(path))
+    }
 
-        // Decompress GZIP file
-        Self::decompress_gzip(&gz_path, output_path).await?;
+    /// Sends a request and returns the decompressed body. With a cassette the
+    /// exchange is recorded, or played back without sending anything.
+    async fn download(&self, request: RequestBuilder) -> Result<Vec<u8>, ApiError> {
+        let built = request
+            .try_clone()
+            .expect("Areabook requests have no streaming body")
+            .build()?;
+
+        let (status, headers, body) = match &self.cassette {
+            Some(cassette) if cassette.is_replay() => cassette.play(&built).await?,
+            cassette => {
+                let (status, headers, body) = self.send(request, &label(&built)).await?;
+                // Failures are recorded too, they're often what a bug report is about
+                if let Some(cassette) = cassette {
+                    cassette.save(&built, status, &headers, &body).await?;
+                }
+                (status, headers, body)
+            }
+        };
 
-        Ok(())
+        Self::check_status(status)?;
+        decode_body(&headers, &body)
     }
 
-    /// Decompresses a GZIP file to the given output path.
-    async fn decompress_gzip(input_path: &str, output_path: &str) -> io::Result<()> {
-        let gz_file = fs::read(input_path).await?;
-        let mut decoder = GzDecoder::new(&gz_file[..]);
-
-        let mut decompressed_data = Vec::new();
-        decoder.read_to_end(&mut decompressed_data)?;
+    /// Sends a request and reads its body, retrying timeouts, dropped
+    /// connections, 429s and 5xxs with backoff. Every attempt waits its turn
+    /// with the rate limiter. Retries are reported on stderr as one line each,
+    /// tagged with `label` since areas are fetched at the same time.
+    async fn send(
+        &self,
+        request: RequestBuilder,
+        label: &str,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), ApiError> {
+        let mut retry = 0;
+        loop {
+            let attempt = request
+                .try_clone()
+                .expect("Areabook requests have no streaming body");
+            self.rate_limiter.wait().await;
+            let can_retry = retry < self.retry.max_retries;
+
+            let wait = match attempt.send().await {
+                Ok(response) if RetryPolicy::is_retryable(response.status()) && can_retry => {
+                    let wait = self
+                        .retry
+                        .retry_after(response.headers())
+                        .unwrap_or_else(|| self.retry.backoff(retry));
+                    eprintln!(
+                        "{label} returned {}, retrying in {:.1}s",
+                        response.status(),
+                        wait.as_secs_f64()
+                    );
+                    wait
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    // A large body can time out or drop halfway through too
+                    match response.bytes().await {
+                        Ok(body) => return Ok((status, headers, body.to_vec())),
+                        Err(e) if RetryPolicy::is_transient(&e) && can_retry => {
+                            let wait = self.retry.backoff(retry);
+                            eprintln!("{label}: {e}, retrying in {:.1}s", wait.as_secs_f64());
+                            wait
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(e) if RetryPolicy::is_transient(&e) && can_retry => {
+                    let wait = self.retry.backoff(retry);
+                    eprintln!("{label}: {e}, retrying in {:.1}s", wait.as_secs_f64());
+                    wait
+                }
+                Err(e) => return Err(e.into()),
+            };
 
-        let mut decompressed_file = File::create(output_path).await?;
-        decompressed_file.write_all(&decompressed_data).await?;
+            tokio::time::sleep(wait).await;
+            retry += 1;
+        }
+    }
 
-        // Remove the GZIP file after decompression
-        fs::remove_file(input_path).await?;
+    /// Turns anything but a 2xx into an error before we try to read the body.
+    fn check_status(status: StatusCode) -> Result<(), ApiError> {
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(ApiError::Unauthorized(status));
+        }
+        if !status.is_success() {
+            return Err(ApiError::Status(status));
+        }
         Ok(())
     }
 
-    pub async fn fetch_key_indicators(
+    /// GETs any Areabook endpoint with the usual device headers plus
+    /// `extra_headers`, returning the decompressed body. Nothing is cached.
+    /// For poking at endpoints we don't have models for yet.
+    pub async fn raw_get(
         &self,
-        info: &MissionaryInfo,
-        output_path: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let url = "https://missionary.churchofjesuschrist.org/ws/areabook/v5.2/key-indicators";
+        path: &str,
+        extra_headers: &[(&str, &str)],
+    ) -> Result<Vec<u8>, ApiError> {
+        let mut request = self.request(path.trim_start_matches('/'));
+        for (name, value) in extra_headers {
+            request = request.header(*name, *value);
+        }
+        self.download(request).await
+    }
 
-        let client = Client::new();
-        let response = client
-            .get(url)
-            .header("Accept-Encoding", "gzip")
-            .header("Accept-Language", "en-US")
-            .header("Authorization", self.get_auth_header())
-            .header("client-version", "6.20.0")
-            .header("Connection", "Keep-Alive")
-            .header("device-type", "Android")
-            .header("forceNoDataGuard", "false")
-            .header("forceUseDataGuard", "false")
-            .header("group-name", "SRG")
-            .header("Host", "missionary.churchofjesuschrist.org")
+    /// Fetches the key indicators for the mission and saves them in the cache.
+    pub async fn fetch_key_indicators(&self, info: &MissionaryInfo) -> Result<(), ApiError> {
+        let area = info.pros_area_id.to_string();
+        let state = SyncState::load(&self.cache, &area).await?;
+        state.save(&self.cache, &area).await?;
+
+        let request = self
+            .request("key-indicators")
             .header("lastSync", "")
             .header("missionId", info.mission_id.to_string())
             .header("prosAreaId", info.pros_area_id.to_string())
             .header("prosAreaName", &info.pros_area_name)
-            .header("syncGuid", "e2b16e2a-98d7-4637-af6c-fdd9e135d731")
-            .header("syncStrategy", "F")
-            .header(
-                "User-Agent",
-                "Area Book 6.20.0 (620037). Android Android SDK built for x86_64. Android 14 (api 34) 81c9cf2ff6e4bdee",
+            .header("syncGuid", &state.sync_guid)
+            .header("syncStrategy", "F");
+        let data = self.download(request).await?;
+        self.cache
+            .write(
+                &Resource::KeyIndicators,
+                &data,
+                &self.meta("key-indicators"),
             )
-            .send()
-            .await?
-            .bytes()
-            .await?;
-
-        let gz_path = format!("{}.gz", output_path);
-        let mut gz_file = File::create(&gz_path).await?;
-        gz_file.write_all(&response).await?;
-
-        // Decompress GZIP file
-        Self::decompress_gzip(&gz_path, output_path).await?;
-
-        Ok(())
+            .await
     }
 
-    /// Reads the commands for an area from the cache or fetches it new
+    /// Reads the commands for an area from the cache, syncing first if the
+    /// cached copy is missing or past its TTL
     pub async fn fetch_commands(
         &self,
         area: &str,
         info: &MissionaryInfo,
-    ) -> Result<Vec<Person>, Box<dyn std::error::Error>> {
-        // Read from the cache
-        let data = match fs::read_to_string(format!("cache/commands/{}.json", area)).await {
-            Ok(d) => d,
-            Err(_) => {
-                let url = "https://missionary.churchofjesuschrist.org/ws/areabook/v5.2/commands";
-
-                let client = Client::new();
-                let response = client
-                    .get(url)
-                    .header("Accept-Encoding", "gzip")
-                    .header("Accept-Language", "en-US")
-                    .header("Authorization", self.get_auth_header())
-                    .header("client-version", "6.20.0")
-                    .header("Connection", "Keep-Alive")
-                    .header("device-type", "Android")
-                    .header("forceNoDataGuard", "false")
-                    .header("forceUseDataGuard", "false")
-                    .header("group-name", "SRG")
-                    .header("Host", "missionary.churchofjesuschrist.org")
-                    .header("lastSync", "")
-                    .header("missionId", info.mission_id.to_string())
-                    .header("prosAreaId", area)
-                    .header("prosAreaName", &info.pros_area_name)
-                    .header("syncGuid", "e2b16e2a-98d7-4637-af6c-fdd9e135d731")
-                    .header("syncStrategy", "F")
-                    .header(
-                        "User-Agent",
-                        "Area Book 6.20.0 (620037). Android Android SDK built for x86_64. Android 14 (api 34) 81c9cf2ff6e4bdee",
-                    )
-                    .send()
-                    .await?
-                    .bytes()
-                    .await?;
-                let gz_path = format!("cache/commands/{area}.gz");
-                let mut gz_file = File::create(&gz_path).await?;
-                gz_file.write_all(&response).await?;
-
-                // Decompress GZIP file
-                Self::decompress_gzip(&gz_path, &format!("cache/commands/{area}.json")).await?;
-                fs::read_to_string(format!("cache/commands/{}.json", area)).await?
+    ) -> Result<Vec<Person>, ApiError> {
+        let resource = Resource::Commands(area.to_string());
+        if self.cache.is_fresh(&resource).await {
+            if let Some(data) = self.cache.read(&resource).await? {
+                return Ok(parse_json::<CommandsResponse>(&data)?.into_people(self.timezone));
             }
-        };
+        }
 
-        let res: CommandsResponse = serde_json::from_str(&data)?;
+        let res = match self.sync_commands(area, info).await {
+            Ok(res) => res,
+            Err(e @ ApiError::Unauthorized(_)) => return Err(e),
+            // An old copy beats no copy
+            Err(e) => match self.cache.read(&resource).await? {
+                Some(data) => {
+                    println!("Failed to sync area {area}, using the cached copy: {e}");
+                    parse_json(&data)?
+                }
+                None => return Err(e),
+            },
+        };
 
-        let mut people_map = HashMap::new();
-        let person_events = res
-            .person_events
-            .iter()
-            .map(|x| (&x.event_id, &x.person_id))
-            .collect::<HashMap<&String, &String>>();
+        Ok(res.into_people(self.timezone))
+    }
 
-        for people in res.contacts {
-            if people.status == 40 {
-                // member
-                continue;
-            }
+    /// Brings the cached commands for an area up to date. The first sync
+    /// downloads everything, later ones only ask for what changed since the
+    /// last one and merge it into the cache.
+    pub async fn sync_commands(
+        &self,
+        area: &str,
+        info: &MissionaryInfo,
+    ) -> Result<CommandsResponse, ApiError> {
+        let resource = Resource::Commands(area.to_string());
+        let mut state = SyncState::load(&self.cache, area).await?;
+
+        // A delta is useless without the full state to apply it to
+        let cached = match self.cache.read(&resource).await? {
+            Some(data) => Some(parse_json::<CommandsResponse>(&data)?),
+            None => None,
+        };
 
-            people_map.insert(
-                people.id,
-                Person {
-                    name: format!(
-                        "{}{}",
-                        people.first_name.unwrap_or_default(),
-                        people.last_name.unwrap_or_default()
-                    ),
-                    language: people.preferred_language_id,
-                    area: res.pros_area_name.clone(),
-                    timeline: Vec::new(),
-                },
-            );
+        // Taken before the request so nothing changed during it gets missed next time
+        let sync_started = Utc::now();
+        let now = sync_started.timestamp_millis() as u64;
+        let full = cached.is_none() || state.full_sync_due(now);
+        if full {
+            state.last_sync = None;
         }
 
-        for c in res.events {
-            if c.start_time.is_none() {
-                continue;
-            }
-            if let Some(person_id) = person_events.get(&c.id) {
-                if let Some(p) = people_map.get_mut(*person_id) {
-                    p.timeline.push(TimelineEntry {
-                        time: c.start_time.unwrap(),
-                        details: c.report.unwrap_or_default(),
-                        kind: TimelineEventKind::Event,
-                    })
-                }
+        let request = self
+            .request("commands")
+            .header("lastSync", state.last_sync_header())
+            .header("missionId", info.mission_id.to_string())
+            .header("prosAreaId", area)
+            .header("prosAreaName", &info.pros_area_name)
+            .header("syncGuid", &state.sync_guid)
+            .header("syncStrategy", state.strategy());
+        let delta = self.download(request).await?;
+        let delta: CommandsResponse = parse_json(&delta)?;
+
+        // A full sync replaces the cached copy, dropping whatever the server deleted
+        let res = match cached {
+            Some(mut cached) if !full => {
+                cached.merge(delta);
+                cached
             }
-        }
+            _ => delta,
+        };
 
-        for c in res.person_drops {
-            if let Some(p) = people_map.get_mut(&c.person_id) {
-                p.timeline.push(TimelineEntry {
-                    time: c.drop_date,
-                    details: c.created_by.unwrap_or_default(),
-                    kind: TimelineEventKind::Drop,
-                })
-            }
+        // Snapshot first, so a failure leaves the cache and sync state as they were.
+        // A replay is something that already happened, not a new sync.
+        if !self.is_replay() {
+            self.history.save(area, sync_started, &res).await?;
         }
 
-        for c in res.person_resets {
-            if let Some(p) = people_map.get_mut(&c.person_id) {
-                p.timeline.push(TimelineEntry {
-                    time: c.reset_date,
-                    details: c.created_by.unwrap_or_default(),
-                    kind: TimelineEventKind::Reset,
-                })
-            }
-        }
+        let data = serde_json::to_vec(&res).expect("CommandsResponse always serializes");
+        self.cache
+            .write(&resource, &data, &self.meta("commands"))
+            .await?;
 
-        for c in res.sacrament_attendance {
-            let naive_date =
-                NaiveDate::parse_from_str(&c.date, "%Y-%m-%d").expect("Invalid date format");
-            let ndt = NaiveDateTime::new(naive_date, NaiveTime::default());
-            let datetime = Utc.from_utc_datetime(&ndt);
-            let timestamp = datetime.timestamp() as u64;
-
-            if let Some(person_id) = person_events.get(&c.id) {
-                if let Some(p) = people_map.get_mut(*person_id) {
-                    p.timeline.push(TimelineEntry {
-                        time: timestamp,
-                        details: String::new(),
-                        kind: TimelineEventKind::Reset,
-                    })
-                }
-            }
+        state.last_sync = Some(now);
+        if full {
+            state.last_full_sync = Some(now);
         }
+        state.save(&self.cache, area).await?;
+
+        Ok(res)
+    }
+}
 
-        let mut people = people_map.into_values().collect::<Vec<Person>>();
-        people
-            .iter_mut()
-            .for_each(|x| x.timeline.sort_by(|a, b| a.time.cmp(&b.time)));
+/// Names a request in retry messages, such as `commands for area 30010`.
+fn label(request: &reqwest::Request) -> String {
+    let path = request
+        .url()
+        .path()
+        .rsplit_once("/v5.2/")
+        .map_or(request.url().path(), |(_, p)| p);
+    match request
+        .headers()
+        .get("prosAreaId")
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(area) => format!("{path} for area {area}"),
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::cassette::{Cassette, Interaction, RecordedRequest, RecordedResponse};
+
+    const BASE_URL: &str = "http://areabook.invalid/v5.2";
+
+    fn commands_cassette(area: &str, mission_id: u64) -> Cassette {
+        let headers = BTreeMap::from([
+            ("missionid".to_string(), mission_id.to_string()),
+            ("prosareaid".to_string(), area.to_string()),
+            ("syncstrategy".to_string(), "F".to_string()),
+        ]);
+        Cassette {
+            interactions: vec![Interaction {
+                request: RecordedRequest {
+                    method: "GET".to_string(),
+                    url: format!("{BASE_URL}/commands"),
+                    headers,
+                },
+                response: RecordedResponse {
+                    status: 200,
+                    headers: BTreeMap::new(),
+                    body: base64::engine::general_purpose::STANDARD
+                        .encode(include_bytes!("../fixtures/commands/30010.json")),
+                },
+            }],
+        }
+    }
 
-        Ok(people)
+    #[tokio::test]
+    async fn replayed_commands_parse_without_touching_history() {
+        let info =
+            MissionaryInfo::try_from(include_str!("../fixtures/user_details.json").to_string())
+                .unwrap();
+        let area = info.pros_area_id.to_string();
+        let dir = std::env::temp_dir().join(format!("presi-test-{}", uuid::Uuid::new_v4()));
+
+        let api = MissionaryApi::new(String::new(), String::new())
+            .with_base_url(BASE_URL)
+            .with_cache(Cache::new(dir.join("cache")))
+            .with_history(SnapshotStore::new(dir.join("history")))
+            .with_cassette(commands_cassette(&area, info.mission_id).into_replay());
+        let people = api.fetch_commands(&area, &info).await;
+        let cached = api.cache().is_fresh(&Resource::Commands(area)).await;
+        let history = dir.join("history").exists();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let people = people.unwrap();
+        assert!(!people.is_empty());
+        assert!(cached);
+        assert!(!history);
     }
 }
@@ -0,0 +1,294 @@
+// Jackson Coxson
+// A local stand-in for the Areabook servers. It serves the fixture files in
+// `fixtures/` the same way the real thing does (gzipped JSON behind Basic auth),
+// so the whole pipeline can run without talking to church servers.
+// MOCK_ENCODING switches the body to deflate, br or identity, or to an HTML
+// error page with `html`.
+//
+// Point the client at it with
+// AREABOOK_BASE_URL=http://127.0.0.1:8080/ws/areabook/v5.2
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use base64::Engine;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+type Rejection = (StatusCode, &'static str);
+
+struct MockServer {
+    fixtures: PathBuf,
+    auth_header: String,
+    mission_id: String,
+    /// Fail every nth request, 0 for never
+    fail_every: usize,
+    /// How response bodies are encoded
+    encoding: String,
+    /// Print the device headers of every request
+    verbose: bool,
+    requests: AtomicUsize,
+}
+
+impl MockServer {
+    /// Builds the header the client is expected to send, encoded the same way the app does.
+    fn expected_auth_header(username: &str, password: &str) -> String {
+        const CUSTOM_ENGINE: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+            &base64::alphabet::URL_SAFE,
+            base64::engine::general_purpose::NO_PAD,
+        );
+        format!(
+            "Basic {}",
+            CUSTOM_ENGINE.encode(format!("{username}:{password}"))
+        )
+    }
+
+    fn check_auth(&self, headers: &HeaderMap) -> Result<(), Rejection> {
+        match headers.get(header::AUTHORIZATION) {
+            Some(h) if h.as_bytes() == self.auth_header.as_bytes() => Ok(()),
+            Some(_) => Err((StatusCode::UNAUTHORIZED, "Bad credentials")),
+            None => Err((StatusCode::UNAUTHORIZED, "Missing Authorization")),
+        }
+    }
+
+    /// Checks the mission headers and returns the requested prosAreaId.
+    fn check_area(&self, headers: &HeaderMap) -> Result<String, Rejection> {
+        let mission_id = headers.get("missionId").and_then(|h| h.to_str().ok());
+        if mission_id != Some(self.mission_id.as_str()) {
+            return Err((StatusCode::FORBIDDEN, "Unknown missionId"));
+        }
+        match headers.get("prosAreaId").and_then(|h| h.to_str().ok()) {
+            Some(area) if !area.is_empty() && area.chars().all(|c| c.is_ascii_digit()) => {
+                Ok(area.to_string())
+            }
+            _ => Err((StatusCode::BAD_REQUEST, "Missing prosAreaId")),
+        }
+    }
+
+    async fn read_fixture(&self, fixture: &str) -> Result<Vec<u8>, Rejection> {
+        tokio::fs::read(self.fixtures.join(fixture))
+            .await
+            .map_err(|_| (StatusCode::NOT_FOUND, "No such fixture"))
+    }
+
+    /// Reads a fixture and sends it back encoded.
+    async fn serve(&self, fixture: &str) -> Result<Response, Rejection> {
+        Ok(self.respond(&self.read_fixture(fixture).await?))
+    }
+
+    /// Sends JSON back with the configured encoding.
+    fn respond(&self, data: &[u8]) -> Response {
+        let body = match self.encoding.as_str() {
+            "html" => {
+                return (
+                    [(header::CONTENT_TYPE, "text/html")],
+                    "<html><head><title>Scheduled Maintenance</title></head></html>",
+                )
+                    .into_response()
+            }
+            "identity" => Ok(data.to_vec()),
+            "deflate" => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            "br" => {
+                let mut body = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut body, 4096, 5, 22);
+                let res = encoder.write_all(data);
+                drop(encoder);
+                res.map(|_| body)
+            }
+            _ => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+        };
+        let body = match body {
+            Ok(b) => b,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
+
+        let encoding = match self.encoding.as_str() {
+            "identity" | "deflate" | "br" => self.encoding.as_str(),
+            _ => "gzip",
+        };
+        (
+            [
+                (header::CONTENT_TYPE, "application/json"),
+                (header::CONTENT_ENCODING, encoding),
+            ],
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// Cuts a full commands response down to what changed after `since`, going by
+/// each record's own timestamp. Good enough to exercise delta syncs.
+fn commands_delta(full: &[u8], since: u64) -> Result<Vec<u8>, Rejection> {
+    let mut res: serde_json::Value = serde_json::from_slice(full)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Bad commands fixture"))?;
+
+    let changed = |v: &serde_json::Value, field: &str| v[field].as_u64().unwrap_or(0) > since;
+    let keep = |res: &mut serde_json::Value, list: &str, field: &str| {
+        if let Some(items) = res[list].as_array_mut() {
+            items.retain(|v| changed(v, field));
+        }
+    };
+    keep(&mut res, "events", "updatedDate");
+    keep(&mut res, "contacts", "createDate");
+    keep(&mut res, "personDrops", "dropDate");
+    keep(&mut res, "personResets", "resetDate");
+    res["sacramentAttendance"] = serde_json::json!([]);
+
+    let events = res["events"]
+        .as_array()
+        .map(|e| e.iter().map(|v| v["id"].clone()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(items) = res["personEvents"].as_array_mut() {
+        items.retain(|v| events.contains(&v["eventId"]));
+    }
+
+    Ok(serde_json::to_vec(&res).expect("Value always serializes"))
+}
+
+/// Makes every `fail_every`th request fail with a 503 and a Retry-After, to
+/// exercise the client's retries. With `verbose` it logs each request too.
+async fn inject_failures(
+    State(server): State<Arc<MockServer>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let n = server.requests.fetch_add(1, Ordering::Relaxed) + 1;
+    if server.verbose {
+        // Handy for seeing what a given device profile sends
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("-")
+                .to_string()
+        };
+        println!(
+            "{} {} client-version={} group-name={} user-agent={}",
+            request.method(),
+            request.uri(),
+            header("client-version"),
+            header("group-name"),
+            header("User-Agent")
+        );
+    }
+    if server.fail_every > 0 && n % server.fail_every == 0 {
+        println!("Injecting failure for request {n}");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "1")],
+            "Injected failure",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+async fn user_details(
+    State(server): State<Arc<MockServer>>,
+    headers: HeaderMap,
+) -> Result<Response, Rejection> {
+    server.check_auth(&headers)?;
+    server.serve("user_details.json").await
+}
+
+async fn key_indicators(
+    State(server): State<Arc<MockServer>>,
+    headers: HeaderMap,
+) -> Result<Response, Rejection> {
+    server.check_auth(&headers)?;
+    server.check_area(&headers)?;
+    server.serve("key_indicators.json").await
+}
+
+async fn commands(
+    State(server): State<Arc<MockServer>>,
+    headers: HeaderMap,
+) -> Result<Response, Rejection> {
+    server.check_auth(&headers)?;
+    let area = server.check_area(&headers)?;
+    let full = server
+        .read_fixture(&format!("commands/{area}.json"))
+        .await?;
+
+    let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+    match (header("syncStrategy"), header("lastSync")) {
+        (Some("D"), Some(last_sync)) => {
+            let since = last_sync
+                .parse::<u64>()
+                .map_err(|_| (StatusCode::BAD_REQUEST, "Bad lastSync"))?;
+            Ok(server.respond(&commands_delta(&full, since)?))
+        }
+        _ => Ok(server.respond(&full)),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenvy::dotenv().ok();
+
+    let addr = std::env::var("MOCK_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let fixtures =
+        PathBuf::from(std::env::var("MOCK_FIXTURES").unwrap_or_else(|_| "fixtures".to_string()));
+    let username = std::env::var("MOCK_USERNAME").unwrap_or_else(|_| "mock".to_string());
+    let password = std::env::var("MOCK_PASSWORD").unwrap_or_else(|_| "mock".to_string());
+
+    // The mission id every request has to carry comes from the user details fixture
+    let details = std::fs::read_to_string(fixtures.join("user_details.json"))
+        .expect("Failed to read user_details.json fixture");
+    let details: serde_json::Value =
+        serde_json::from_str(&details).expect("user_details.json fixture is not JSON");
+    let mission_id = details["missionId"]
+        .as_u64()
+        .expect("user_details.json fixture has no missionId")
+        .to_string();
+
+    let server = Arc::new(MockServer {
+        fixtures,
+        auth_header: MockServer::expected_auth_header(&username, &password),
+        mission_id,
+        fail_every: std::env::var("MOCK_FAIL_EVERY")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0),
+        encoding: std::env::var("MOCK_ENCODING").unwrap_or_else(|_| "gzip".to_string()),
+        verbose: std::env::var("MOCK_VERBOSE").is_ok(),
+        requests: AtomicUsize::new(0),
+    });
+
+    let app = Router::new().nest(
+        "/ws/areabook/v5.2",
+        Router::new()
+            .route("/user/details", get(user_details))
+            .route("/key-indicators", get(key_indicators))
+            .route("/commands", get(commands))
+            .layer(middleware::from_fn_with_state(
+                server.clone(),
+                inject_failures,
+            ))
+            .with_state(server),
+    );
+
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .expect("Failed to bind mock server");
+    println!("Mock Areabook listening on http://{addr}/ws/areabook/v5.2");
+    axum::serve(listener, app)
+        .await
+        .expect("Mock server failed");
+}
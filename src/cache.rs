@@ -0,0 +1,260 @@
+// Jackson Coxson
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::crypto::{self, CacheKey};
+use crate::error::{parse_json, ApiError};
+
+/// Something from Areabook we keep a copy of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    UserDetails,
+    KeyIndicators,
+    /// The commands for one prosAreaId
+    Commands(String),
+}
+
+impl Resource {
+    /// Where the resource lives, relative to the cache root.
+    fn relative_path(&self) -> PathBuf {
+        match self {
+            Resource::UserDetails => PathBuf::from("info.json"),
+            Resource::KeyIndicators => PathBuf::from("kics.json"),
+            Resource::Commands(area) => PathBuf::from("commands").join(format!("{area}.json")),
+        }
+    }
+
+    /// How long a copy is good for before it gets fetched again.
+    pub fn ttl(&self) -> Duration {
+        match self {
+            // Only changes at transfers
+            Resource::UserDetails => Duration::from_secs(7 * 24 * 60 * 60),
+            Resource::KeyIndicators => Duration::from_secs(24 * 60 * 60),
+            Resource::Commands(_) => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl std::fmt::Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resource::UserDetails => write!(f, "user details"),
+            Resource::KeyIndicators => write!(f, "key indicators"),
+            Resource::Commands(area) => write!(f, "area {area}"),
+        }
+    }
+}
+
+/// Stored next to every cached resource as `{name}.meta.json`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheMeta {
+    /// Unix timestamp in milliseconds
+    pub fetched_at: u64,
+    pub client_version: String,
+    pub source_url: String,
+}
+
+impl CacheMeta {
+    pub fn new(client_version: &str, source_url: &str) -> Self {
+        Self {
+            fetched_at: Utc::now().timestamp_millis() as u64,
+            client_version: client_version.to_string(),
+            source_url: source_url.to_string(),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        let now = Utc::now().timestamp_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.fetched_at))
+    }
+}
+
+/// Everything under `cache/`.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    root: PathBuf,
+    /// Encrypts resources as they're written. Metadata and sync state don't
+    /// hold personal data and stay plaintext.
+    key: Option<Arc<CacheKey>>,
+}
+
+impl Cache {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            key: None,
+        }
+    }
+
+    pub fn with_key(mut self, key: Arc<CacheKey>) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn key(&self) -> Option<&Arc<CacheKey>> {
+        self.key.as_ref()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn path(&self, resource: &Resource) -> PathBuf {
+        self.root.join(resource.relative_path())
+    }
+
+    fn meta_path(&self, resource: &Resource) -> PathBuf {
+        self.path(resource).with_extension("meta.json")
+    }
+
+    /// Reads a resource, or `None` if it was never fetched. Encrypted
+    /// resources are decrypted on the way out.
+    pub async fn read(&self, resource: &Resource) -> Result<Option<String>, ApiError> {
+        let data = match fs::read(self.path(resource)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let data = crypto::open(self.key.as_deref(), data)?;
+        String::from_utf8(data)
+            .map(Some)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e).into())
+    }
+
+    /// Stores a resource along with where and when it came from.
+    pub async fn write(
+        &self,
+        resource: &Resource,
+        data: &[u8],
+        meta: &CacheMeta,
+    ) -> Result<(), ApiError> {
+        let path = self.path(resource);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(&path, crypto::seal(self.key.as_deref(), data)).await?;
+
+        let meta = serde_json::to_string_pretty(meta).expect("CacheMeta always serializes");
+        fs::write(self.meta_path(resource), meta).await?;
+        Ok(())
+    }
+
+    /// Where a resource came from, if it's cached and was fetched since
+    /// metadata has been kept.
+    pub async fn meta(&self, resource: &Resource) -> Option<CacheMeta> {
+        let data = fs::read_to_string(self.meta_path(resource)).await.ok()?;
+        parse_json(&data).ok()
+    }
+
+    /// Whether the resource is cached and younger than its TTL.
+    pub async fn is_fresh(&self, resource: &Resource) -> bool {
+        match self.meta(resource).await {
+            Some(meta) => {
+                fs::try_exists(self.path(resource)).await.unwrap_or(false)
+                    && meta.age() < resource.ttl()
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets a resource so the next run fetches it from scratch.
+    pub async fn invalidate(&self, resource: &Resource) -> Result<(), ApiError> {
+        for path in [self.path(resource), self.meta_path(resource)] {
+            match fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything currently cached.
+    pub async fn resources(&self) -> Result<Vec<Resource>, ApiError> {
+        let mut resources = Vec::new();
+        for resource in [Resource::UserDetails, Resource::KeyIndicators] {
+            if fs::try_exists(self.path(&resource)).await? {
+                resources.push(resource);
+            }
+        }
+
+        let mut areas = Vec::new();
+        match fs::read_dir(self.root.join("commands")).await {
+            Ok(mut dir) => {
+                while let Some(entry) = dir.next_entry().await? {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if let Some(area) = name.strip_suffix(".json") {
+                        if !area.ends_with(".meta") {
+                            areas.push(area.to_string());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        areas.sort();
+        resources.extend(areas.into_iter().map(Resource::Commands));
+
+        Ok(resources)
+    }
+
+    /// Prints what's cached, how old it is and where it came from.
+    pub async fn print_status(&self) -> Result<(), ApiError> {
+        let resources = self.resources().await?;
+        if resources.is_empty() {
+            println!("Nothing cached in {}", self.root.display());
+            return Ok(());
+        }
+
+        for resource in resources {
+            let ttl_hours = resource.ttl().as_secs() / 3600;
+            match self.meta(&resource).await {
+                Some(meta) => {
+                    let age_hours = meta.age().as_secs_f64() / 3600.0;
+                    let state = if meta.age() < resource.ttl() {
+                        "fresh"
+                    } else {
+                        "stale"
+                    };
+                    println!(
+                        "{resource}: {state}, {age_hours:.1}h old (ttl {ttl_hours}h), client {} from {}",
+                        meta.client_version, meta.source_url
+                    );
+                }
+                None => println!("{resource}: stale, no metadata (ttl {ttl_hours}h)"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypts every plaintext resource in place, shredding the originals.
+    /// Returns how many were encrypted.
+    pub async fn encrypt_all(&self) -> Result<usize, ApiError> {
+        let key = self
+            .key
+            .as_deref()
+            .ok_or(ApiError::Encryption("no passphrase"))?;
+        let mut count = 0;
+        crypto::unlock(&self.root, key).await?;
+        for resource in self.resources().await? {
+            if crypto::encrypt_file(key, &self.path(&resource)).await? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Deletes the whole cache, sync state included.
+    pub async fn purge(&self) -> Result<(), ApiError> {
+        match fs::remove_dir_all(&self.root).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
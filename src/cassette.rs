@@ -0,0 +1,212 @@
+// Jackson Coxson
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64::Engine;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::crypto::{self, CacheKey};
+use crate::error::{parse_json, ApiError};
+
+/// Headers whose values never make it into a cassette.
+const SCRUBBED: [&str; 3] = ["authorization", "cookie", "set-cookie"];
+/// Request headers that have to match for a recorded response to be replayed.
+const MATCHED: [&str; 3] = ["missionid", "prosareaid", "syncstrategy"];
+
+/// One request and what the server said to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    /// Base64 of the body exactly as it came over the wire, still compressed
+    pub body: String,
+}
+
+/// Everything recorded in one run, in the order it happened.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+/// Whether the API client talks to the server, records what it says, or
+/// plays back an earlier recording instead.
+#[derive(Debug)]
+pub enum CassetteMode {
+    Record {
+        path: PathBuf,
+        cassette: Mutex<Cassette>,
+        /// The cache's key, since a cassette holds the same personal data
+        key: Option<Arc<CacheKey>>,
+    },
+    Replay {
+        cassette: Cassette,
+        /// Which interactions have been played already
+        used: Mutex<Vec<bool>>,
+    },
+}
+
+impl CassetteMode {
+    /// Records into `path`, replacing whatever is there. Encrypted when
+    /// there's a key.
+    pub fn record<P: AsRef<Path>>(path: P, key: Option<Arc<CacheKey>>) -> Self {
+        CassetteMode::Record {
+            path: path.as_ref().to_path_buf(),
+            cassette: Mutex::new(Cassette::default()),
+            key,
+        }
+    }
+
+    /// Plays back the cassette at `path`.
+    pub async fn replay<P: AsRef<Path>>(path: P, key: Option<&CacheKey>) -> Result<Self, ApiError> {
+        Ok(Cassette::load(path, key).await?.into_replay())
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self, CassetteMode::Replay { .. })
+    }
+
+    /// Adds an exchange to the cassette and saves it, so a crash mid-run still
+    /// leaves everything up to it recorded.
+    pub async fn save(
+        &self,
+        request: &reqwest::Request,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), ApiError> {
+        let CassetteMode::Record {
+            path,
+            cassette,
+            key,
+        } = self
+        else {
+            return Ok(());
+        };
+
+        let interaction = Interaction {
+            request: RecordedRequest {
+                method: request.method().to_string(),
+                url: request.url().to_string(),
+                headers: scrub(request.headers()),
+            },
+            response: RecordedResponse {
+                status: status.as_u16(),
+                headers: scrub(headers),
+                body: base64::engine::general_purpose::STANDARD.encode(body),
+            },
+        };
+
+        let mut cassette = cassette.lock().await;
+        cassette.interactions.push(interaction);
+        let json = serde_json::to_vec_pretty(&*cassette).expect("Cassette always serializes");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, crypto::seal(key.as_deref(), &json)).await?;
+        Ok(())
+    }
+
+    /// The first unplayed response recorded for the same method, URL and area
+    /// headers as `request`. Replaying the same cassette always gives the
+    /// same answers in the same order.
+    pub async fn play(
+        &self,
+        request: &reqwest::Request,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), ApiError> {
+        let CassetteMode::Replay { cassette, used } = self else {
+            unreachable!("play is only called when replaying");
+        };
+
+        let headers = scrub(request.headers());
+        let mut used = used.lock().await;
+        let found = cassette.interactions.iter().enumerate().find(|(i, x)| {
+            !used[*i]
+                && x.request.method == request.method().as_str()
+                && x.request.url == request.url().as_str()
+                && MATCHED
+                    .iter()
+                    .all(|h| x.request.headers.get(*h) == headers.get(*h))
+        });
+        let Some((i, interaction)) = found else {
+            return Err(ApiError::NotRecorded(format!(
+                "{} {}",
+                request.method(),
+                request.url()
+            )));
+        };
+        used[i] = true;
+        interaction.response.parts()
+    }
+}
+
+impl Cassette {
+    pub async fn load<P: AsRef<Path>>(path: P, key: Option<&CacheKey>) -> Result<Self, ApiError> {
+        parse_json(crypto::open(key, fs::read(path).await?)?)
+    }
+
+    pub fn into_replay(self) -> CassetteMode {
+        let used = Mutex::new(vec![false; self.interactions.len()]);
+        CassetteMode::Replay {
+            cassette: self,
+            used,
+        }
+    }
+}
+
+impl RecordedResponse {
+    /// The status, headers and raw body, as if they just came off the wire.
+    pub fn parts(&self) -> Result<(StatusCode, HeaderMap, Vec<u8>), ApiError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        let body = base64::engine::general_purpose::STANDARD
+            .decode(&self.body)
+            .map_err(|e| ApiError::Decompress {
+                encoding: "base64".to_string(),
+                source: std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            })?;
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        Ok((status, headers, body))
+    }
+}
+
+/// Lowercased headers with the secret ones blanked out.
+fn scrub(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.as_str().to_ascii_lowercase();
+            let value = if SCRUBBED.contains(&name.as_str()) {
+                "[scrubbed]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            };
+            (name, value)
+        })
+        .collect()
+}
@@ -0,0 +1,163 @@
+// Jackson Coxson
+//! The numeric codes Areabook uses for statuses and types.
+//!
+//! Areabook never says what its codes mean, so these come from comparing
+//! captures with what the app showed. Only `ContactStatus::Member` (40) has
+//! been relied on since the start and is verified; the rest are our best
+//! reading and unverified until someone checks them against the app. Codes not
+//! listed here parse as `Unknown(n)` instead of failing, so a new code only
+//! costs a label.
+//!
+//! | Field                           | Code | Variant          | Verified |
+//! | ------------------------------- | ---- | ---------------- | -------- |
+//! | `contacts[].status`             | 1    | `New`            | no       |
+//! |                                 | 10   | `BeingTaught`    | no       |
+//! |                                 | 20   | `Progressing`    | no       |
+//! |                                 | 30   | `NotProgressing` | no       |
+//! |                                 | 40   | `Member`         | yes      |
+//! | `contacts[].contactSource`      | 1    | `Missionaries`   | no       |
+//! |                                 | 2    | `MemberReferral` | no       |
+//! |                                 | 3    | `OnlineReferral` | no       |
+//! |                                 | 4    | `Other`          | no       |
+//! | `contacts[].visibilityTypeId`   | 1    | `Area`           | no       |
+//! | `contacts[].ownerStatus`        | 1    | `Owner`          | no       |
+//! | `contacts[].ageCategory`        | 1    | `Child`          | no       |
+//! |                                 | 2    | `Youth`          | no       |
+//! |                                 | 3    | `Adult`          | no       |
+//! | `events[].eventType`            | 1    | `Contact`        | no       |
+//! |                                 | 2    | `Task`           | no       |
+//! | `events[].contactTypeId`        | 1    | `InPerson`       | no       |
+//! |                                 | 2    | `Phone`          | no       |
+//! |                                 | 3    | `Text`           | no       |
+//! | `personDrops[].status`          | 1    | `NotInterested`  | no       |
+//! |                                 | 2    | `Unreachable`    | no       |
+
+use serde::{Deserialize, Serialize};
+
+/// Defines a code enum with an `Unknown(n)` fallback, a label for each code,
+/// and serde going through the number so it round-trips unchanged. Not
+/// `serde_repr`, which can't keep the number of a code it doesn't know.
+macro_rules! code_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($(#[$variant_meta:meta])* $variant:ident = $code:literal => $label:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[serde(from = "u32", into = "u32")]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)*
+            /// A code we don't have a name for yet
+            Unknown(u32),
+        }
+
+        impl $name {
+            /// What the app calls it, as best we know.
+            pub fn label(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $label,)*
+                    $name::Unknown(_) => "unknown",
+                }
+            }
+        }
+
+        impl From<u32> for $name {
+            fn from(code: u32) -> Self {
+                match code {
+                    $($code => $name::$variant,)*
+                    n => $name::Unknown(n),
+                }
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $code,)*
+                    $name::Unknown(n) => n,
+                }
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $name::Unknown(n) => write!(f, "unknown ({n})"),
+                    known => f.write_str(known.label()),
+                }
+            }
+        }
+    };
+}
+
+code_enum! {
+    /// Where someone is at, `contacts[].status`.
+    pub enum ContactStatus {
+        New = 1 => "new",
+        BeingTaught = 10 => "being taught",
+        Progressing = 20 => "progressing",
+        NotProgressing = 30 => "not progressing",
+        /// Already a member, left out of the teaching stats
+        Member = 40 => "member",
+    }
+}
+
+code_enum! {
+    /// How a contact was found, `contacts[].contactSource`.
+    pub enum ContactSource {
+        Missionaries = 1 => "found by missionaries",
+        MemberReferral = 2 => "member referral",
+        OnlineReferral = 3 => "online referral",
+        Other = 4 => "other",
+    }
+}
+
+code_enum! {
+    /// Who can see a contact, `contacts[].visibilityTypeId`.
+    pub enum VisibilityType {
+        Area = 1 => "area",
+    }
+}
+
+code_enum! {
+    /// Whether the area owns a contact, `contacts[].ownerStatus`.
+    pub enum OwnerStatus {
+        Owner = 1 => "owner",
+    }
+}
+
+code_enum! {
+    /// `contacts[].ageCategory`.
+    pub enum AgeCategory {
+        Child = 1 => "child",
+        Youth = 2 => "youth",
+        Adult = 3 => "adult",
+    }
+}
+
+code_enum! {
+    /// `events[].eventType`.
+    pub enum EventType {
+        Contact = 1 => "contact",
+        Task = 2 => "task",
+    }
+}
+
+code_enum! {
+    /// How the missionaries got in touch, `events[].contactTypeId`.
+    pub enum ContactType {
+        InPerson = 1 => "in person",
+        Phone = 2 => "phone call",
+        Text = 3 => "text message",
+    }
+}
+
+code_enum! {
+    /// Why someone was dropped, `personDrops[].status`.
+    pub enum DropStatus {
+        NotInterested = 1 => "not interested",
+        Unreachable = 2 => "couldn't be reached",
+    }
+}
@@ -0,0 +1,224 @@
+// Jackson Coxson
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::ApiError;
+
+/// Starts every encrypted file, followed by the salt, the nonce and the ciphertext.
+const MAGIC: &[u8] = b"ABENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Name of the file that marks a directory as holding encrypted data. It
+/// holds `VERIFIER` encrypted, so a wrong passphrase is caught before anything
+/// gets written with it.
+pub const MARKER: &str = ".encrypted";
+const VERIFIER: &[u8] = b"areabook cache";
+
+/// Encrypts what we store on disk with a key derived from a passphrase.
+///
+/// Every file carries the salt its key was derived with, so files written in
+/// different runs (or before a purge) can still be read. Keys are derived once
+/// per salt since Argon2 is deliberately slow.
+pub struct CacheKey {
+    passphrase: String,
+    /// The salt for everything this run writes
+    salt: [u8; SALT_LEN],
+    keys: Mutex<HashMap<[u8; SALT_LEN], XChaCha20Poly1305>>,
+}
+
+impl std::fmt::Debug for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CacheKey")
+    }
+}
+
+impl CacheKey {
+    pub fn new(passphrase: String) -> Self {
+        Self {
+            passphrase,
+            salt: rand::random(),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cipher(&self, salt: [u8; SALT_LEN]) -> XChaCha20Poly1305 {
+        let mut keys = self.keys.lock().expect("key cache poisoned");
+        keys.entry(salt)
+            .or_insert_with(|| {
+                let mut key = [0u8; 32];
+                Argon2::default()
+                    .hash_password_into(self.passphrase.as_bytes(), &salt, &mut key)
+                    .expect("32 byte keys and 16 byte salts are valid for Argon2");
+                XChaCha20Poly1305::new(&Key::from(key))
+            })
+            .clone()
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher(self.salt)
+            .encrypt(&XNonce::from(nonce), plaintext)
+            .expect("XChaCha20Poly1305 takes any length of plaintext");
+
+        let mut data = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.salt);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        data
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, ApiError> {
+        let header = MAGIC.len() + SALT_LEN + NONCE_LEN;
+        if !is_encrypted(data) || data.len() < header {
+            return Err(ApiError::Encryption("not an encrypted file"));
+        }
+
+        let salt: [u8; SALT_LEN] = data[MAGIC.len()..MAGIC.len() + SALT_LEN]
+            .try_into()
+            .expect("slice is SALT_LEN long");
+        let nonce: [u8; NONCE_LEN] = data[MAGIC.len() + SALT_LEN..header]
+            .try_into()
+            .expect("slice is NONCE_LEN long");
+        self.cipher(salt)
+            .decrypt(&XNonce::from(nonce), &data[header..])
+            .map_err(|_| ApiError::Encryption("wrong passphrase or corrupted file"))
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypts with the key if there is one, otherwise passes the data through.
+pub fn seal(key: Option<&CacheKey>, data: &[u8]) -> Vec<u8> {
+    match key {
+        Some(key) => key.encrypt(data),
+        None => data.to_vec(),
+    }
+}
+
+/// Decrypts encrypted data, and passes plaintext from before encryption was
+/// turned on straight through.
+pub fn open(key: Option<&CacheKey>, data: Vec<u8>) -> Result<Vec<u8>, ApiError> {
+    match (is_encrypted(&data), key) {
+        (false, _) => Ok(data),
+        (true, Some(key)) => key.decrypt(&data),
+        (true, None) => Err(ApiError::Encryption(
+            "data is encrypted, set AREABOOK_CACHE_PASSPHRASE",
+        )),
+    }
+}
+
+/// Checks `key` against the marker in `root`, or marks `root` as encrypted
+/// with it if there's no marker yet.
+pub async fn unlock(root: &Path, key: &CacheKey) -> Result<(), ApiError> {
+    let marker = root.join(MARKER);
+    match fs::read(&marker).await {
+        Ok(data) => {
+            if key.decrypt(&data)? != VERIFIER {
+                return Err(ApiError::Encryption("wrong passphrase or corrupted file"));
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            fs::create_dir_all(root).await?;
+            fs::write(&marker, key.encrypt(VERIFIER)).await?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Overwrites a file with zeros before deleting it. On SSDs and copy-on-write
+/// filesystems the old blocks may survive anyway, but it's the best we can do
+/// from here.
+pub async fn shred(path: &Path) -> Result<(), ApiError> {
+    let len = fs::metadata(path).await?.len();
+    let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+    file.write_all(&vec![0u8; len as usize]).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::remove_file(path).await?;
+    Ok(())
+}
+
+/// Replaces a plaintext file with its encrypted version, shredding the original.
+/// Returns false if the file was already encrypted.
+pub async fn encrypt_file(key: &CacheKey, path: &Path) -> Result<bool, ApiError> {
+    let data = fs::read(path).await?;
+    if is_encrypted(&data) {
+        return Ok(false);
+    }
+
+    let tmp = path.with_extension("enc.tmp");
+    fs::write(&tmp, key.encrypt(&data)).await?;
+    shred(path).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = CacheKey::new("correct horse".to_string());
+        let data = key.encrypt(b"Ann Brown, 801-555-1234");
+        assert!(is_encrypted(&data));
+        assert!(!data.windows(9).any(|w| w == b"Ann Brown"));
+        assert_eq!(key.decrypt(&data).unwrap(), b"Ann Brown, 801-555-1234");
+
+        // Another run has another salt, but the passphrase is all it needs
+        let later = CacheKey::new("correct horse".to_string());
+        assert_eq!(later.decrypt(&data).unwrap(), b"Ann Brown, 801-555-1234");
+    }
+
+    #[test]
+    fn wrong_passphrase_fails() {
+        let data = CacheKey::new("correct horse".to_string()).encrypt(b"secret");
+        let wrong = CacheKey::new("correct hose".to_string());
+        assert!(matches!(wrong.decrypt(&data), Err(ApiError::Encryption(_))));
+        assert!(wrong.decrypt(b"plain").is_err());
+    }
+
+    #[test]
+    fn seal_and_open_pass_plaintext_through() {
+        let key = CacheKey::new("correct horse".to_string());
+        assert_eq!(seal(None, b"plain"), b"plain");
+        assert_eq!(open(Some(&key), b"plain".to_vec()).unwrap(), b"plain");
+        let sealed = seal(Some(&key), b"secret");
+        assert!(open(None, sealed.clone()).is_err());
+        assert_eq!(open(Some(&key), sealed).unwrap(), b"secret");
+    }
+
+    #[tokio::test]
+    async fn unlock_checks_the_passphrase() {
+        let root = std::env::temp_dir().join(format!("presi-test-{}", uuid::Uuid::new_v4()));
+        let key = CacheKey::new("correct horse".to_string());
+
+        let first = unlock(&root, &key).await;
+        let marker = std::fs::read(root.join(MARKER));
+        let again = unlock(&root, &CacheKey::new("correct horse".to_string())).await;
+        let wrong = unlock(&root, &CacheKey::new("correct hose".to_string())).await;
+        let after = std::fs::read(root.join(MARKER));
+        let _ = std::fs::remove_dir_all(&root);
+
+        first.unwrap();
+        again.unwrap();
+        assert!(matches!(wrong, Err(ApiError::Encryption(_))));
+        // A wrong passphrase leaves the marker alone
+        assert_eq!(marker.unwrap(), after.unwrap());
+    }
+}
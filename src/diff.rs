@@ -0,0 +1,324 @@
+// Jackson Coxson
+
+use std::collections::HashMap;
+
+use chrono::DateTime;
+
+use crate::timeline::{CommandsResponse, Contact, Drop, Event, Reset, SacramentAttendance};
+
+/// What changed in an area between two snapshots.
+pub struct AreaDiff<'a> {
+    /// Every contact in either snapshot by id, the newer copy where there's both
+    contacts: HashMap<&'a str, &'a Contact>,
+    /// Who each event in the newer snapshot was with
+    event_people: HashMap<&'a str, Vec<&'a str>>,
+    pub contacts_added: Vec<&'a Contact>,
+    pub contacts_removed: Vec<&'a Contact>,
+    /// The contact before and after `prosAreaId` or `lastReassignedDate` changed
+    pub contacts_reassigned: Vec<(&'a Contact, &'a Contact)>,
+    pub dropped: Vec<&'a Drop>,
+    pub reset: Vec<&'a Reset>,
+    pub events_added: Vec<&'a Event>,
+    /// Events whose `updatedDate` changed
+    pub events_edited: Vec<&'a Event>,
+    pub sacrament_attendance: Vec<&'a SacramentAttendance>,
+}
+
+impl<'a> AreaDiff<'a> {
+    pub fn between(from: &'a CommandsResponse, to: &'a CommandsResponse) -> Self {
+        let old_contacts = by_id(&from.contacts, |c| &c.id);
+        let new_contacts = by_id(&to.contacts, |c| &c.id);
+        let old_events = by_id(&from.events, |e| &e.id);
+        let old_drops = by_id(&from.person_drops, |d| &d.id);
+        let old_resets = by_id(&from.person_resets, |r| &r.id);
+        let old_sacrament = by_id(&from.sacrament_attendance, |s| &s.id);
+
+        let mut contacts = HashMap::new();
+        for c in from.contacts.iter().chain(&to.contacts) {
+            contacts.insert(c.id.as_str(), c);
+        }
+        let mut event_people: HashMap<&str, Vec<&str>> = HashMap::new();
+        for pe in &to.person_events {
+            event_people
+                .entry(pe.event_id.as_str())
+                .or_default()
+                .push(pe.person_id.as_str());
+        }
+
+        let mut diff = Self {
+            contacts,
+            event_people,
+            contacts_added: Vec::new(),
+            contacts_removed: Vec::new(),
+            contacts_reassigned: Vec::new(),
+            dropped: Vec::new(),
+            reset: Vec::new(),
+            events_added: Vec::new(),
+            events_edited: Vec::new(),
+            sacrament_attendance: Vec::new(),
+        };
+
+        for c in &to.contacts {
+            match old_contacts.get(&c.id) {
+                None => diff.contacts_added.push(c),
+                Some(old)
+                    if old.pros_area_id.flatten() != c.pros_area_id.flatten()
+                        || old.last_reassigned_date.flatten()
+                            != c.last_reassigned_date.flatten() =>
+                {
+                    diff.contacts_reassigned.push((old, c))
+                }
+                Some(_) => {}
+            }
+        }
+        diff.contacts_removed = from
+            .contacts
+            .iter()
+            .filter(|c| !new_contacts.contains_key(&c.id))
+            .collect();
+
+        for e in &to.events {
+            match old_events.get(&e.id) {
+                None => diff.events_added.push(e),
+                Some(old) if old.updated_date.flatten() != e.updated_date.flatten() => {
+                    diff.events_edited.push(e)
+                }
+                Some(_) => {}
+            }
+        }
+
+        diff.dropped = to
+            .person_drops
+            .iter()
+            .filter(|d| !old_drops.contains_key(&d.id))
+            .collect();
+        diff.reset = to
+            .person_resets
+            .iter()
+            .filter(|r| !old_resets.contains_key(&r.id))
+            .collect();
+        diff.sacrament_attendance = to
+            .sacrament_attendance
+            .iter()
+            .filter(|s| !old_sacrament.contains_key(&s.id))
+            .collect();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts_added.is_empty()
+            && self.contacts_removed.is_empty()
+            && self.contacts_reassigned.is_empty()
+            && self.dropped.is_empty()
+            && self.reset.is_empty()
+            && self.events_added.is_empty()
+            && self.events_edited.is_empty()
+            && self.sacrament_attendance.is_empty()
+    }
+
+    /// A contact's name from either snapshot, or its id if it's in neither.
+    fn name(&self, person_id: &str) -> String {
+        self.contacts
+            .get(person_id)
+            .map(|c| c.display_name())
+            .unwrap_or_else(|| person_id.to_string())
+    }
+
+    /// Who an event was with, going by the newer snapshot.
+    fn participants(&self, event_id: &str) -> String {
+        let names = self
+            .event_people
+            .get(event_id)
+            .into_iter()
+            .flatten()
+            .map(|id| self.name(id))
+            .collect::<Vec<String>>();
+        if names.is_empty() {
+            "nobody".to_string()
+        } else {
+            names.join(", ")
+        }
+    }
+
+    pub fn print(&self) {
+        for c in &self.contacts_added {
+            println!("  + new contact {} ({})", c.display_name(), c.status);
+        }
+        for c in &self.contacts_removed {
+            println!("  - removed contact {}", c.display_name());
+        }
+        for (old, new) in &self.contacts_reassigned {
+            println!(
+                "  ~ reassigned {} from area {} to {}",
+                new.display_name(),
+                area_id(old.pros_area_id.flatten()),
+                area_id(new.pros_area_id.flatten())
+            );
+        }
+        for d in &self.dropped {
+            println!(
+                "  - dropped {} on {}, {}{}",
+                self.name(&d.person_id),
+                format_millis(d.drop_date),
+                d.status,
+                d.note
+                    .as_ref()
+                    .and_then(Option::as_deref)
+                    .map(|n| format!(": {n}"))
+                    .unwrap_or_default()
+            );
+        }
+        for r in &self.reset {
+            println!(
+                "  + reset {} on {}",
+                self.name(&r.person_id),
+                format_millis(r.reset_date)
+            );
+        }
+        for e in &self.events_added {
+            println!("  + {}", describe_event(e, &self.participants(&e.id)));
+        }
+        for e in &self.events_edited {
+            println!(
+                "  ~ edited {}",
+                describe_event(e, &self.participants(&e.id))
+            );
+        }
+        for s in &self.sacrament_attendance {
+            println!(
+                "  + {} attended sacrament meeting on {}",
+                self.name(&s.person_id),
+                s.date
+            );
+        }
+    }
+}
+
+fn by_id<T>(items: &[T], id: fn(&T) -> &String) -> HashMap<&String, &T> {
+    items.iter().map(|x| (id(x), x)).collect()
+}
+
+fn area_id(id: Option<u32>) -> String {
+    id.map(|a| a.to_string())
+        .unwrap_or_else(|| "none".to_string())
+}
+
+fn describe_event(e: &Event, participants: &str) -> String {
+    format!(
+        "{} \"{}\" with {participants}{}{}",
+        e.event_type,
+        e.subject
+            .as_ref()
+            .and_then(Option::as_deref)
+            .unwrap_or("untitled"),
+        e.start_time
+            .flatten()
+            .map(|t| format!(" on {}", format_millis(t)))
+            .unwrap_or_default(),
+        e.contact_type_id
+            .flatten()
+            .map(|t| format!(" ({t})"))
+            .unwrap_or_default()
+    )
+}
+
+/// A Unix timestamp in milliseconds as YYYY-MM-DD.
+pub fn format_millis(t: u64) -> String {
+    DateTime::from_timestamp_millis(t as i64)
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| t.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{commands, contact, event, with};
+    use serde_json::json;
+
+    fn ids<'a, T>(items: &[&'a T], id: fn(&'a T) -> &'a String) -> Vec<&'a str> {
+        items.iter().map(|x| id(x).as_str()).collect()
+    }
+
+    /// p1 to p3 with a lesson, a drop, a reset and sacrament on p1.
+    fn before() -> CommandsResponse {
+        commands(json!({
+            "personEvents": [{ "id": "pe1", "personId": "p1", "eventId": "e1" }],
+            "events": [with(event("e1", 0), json!({ "updatedDate": 1 }))],
+            "personDrops": [{ "id": "d1", "personId": "p1", "dropDate": 0, "status": 1 }],
+            "personResets": [{ "id": "r1", "personId": "p1", "resetDate": 0 }],
+            "sacramentAttendance": [{ "id": "s1", "personId": "p1", "date": "2024-03-03" }],
+            "contacts": [
+                contact("p1"),
+                with(contact("p2"), json!({ "prosAreaId": 10 })),
+                contact("p3"),
+            ],
+        }))
+    }
+
+    #[test]
+    fn nothing_changed() {
+        let (from, to) = (before(), before());
+        assert!(AreaDiff::between(&from, &to).is_empty());
+    }
+
+    #[test]
+    fn changes_are_sorted_by_kind() {
+        let from = before();
+        let to = commands(json!({
+            "personEvents": [
+                { "id": "pe1", "personId": "p1", "eventId": "e1" },
+                { "id": "pe2", "personId": "p3", "eventId": "e2" },
+                { "id": "pe3", "personId": "p4", "eventId": "e2" },
+            ],
+            "events": [
+                with(event("e1", 0), json!({ "updatedDate": 2 })),
+                event("e2", 0),
+            ],
+            "personDrops": [
+                { "id": "d1", "personId": "p1", "dropDate": 0, "status": 1 },
+                { "id": "d2", "personId": "p3", "dropDate": 0, "status": 1 },
+            ],
+            "personResets": [
+                { "id": "r1", "personId": "p1", "resetDate": 0 },
+                { "id": "r2", "personId": "p3", "resetDate": 0 },
+            ],
+            "sacramentAttendance": [
+                { "id": "s1", "personId": "p1", "date": "2024-03-03" },
+                { "id": "s2", "personId": "p2", "date": "2024-03-10" },
+            ],
+            "contacts": [
+                contact("p1"),
+                with(contact("p2"), json!({ "prosAreaId": 20 })),
+                with(contact("p4"), json!({ "first": "Dee" })),
+            ],
+        }));
+        let diff = AreaDiff::between(&from, &to);
+
+        assert_eq!(ids(&diff.contacts_added, |c| &c.id), ["p4"]);
+        assert_eq!(ids(&diff.contacts_removed, |c| &c.id), ["p3"]);
+        let reassigned = diff
+            .contacts_reassigned
+            .iter()
+            .map(|(old, new)| (old.id.as_str(), old.pros_area_id, new.pros_area_id))
+            .collect::<Vec<_>>();
+        assert_eq!(reassigned, [("p2", Some(Some(10)), Some(Some(20)))]);
+        assert_eq!(ids(&diff.dropped, |d| &d.id), ["d2"]);
+        assert_eq!(ids(&diff.reset, |r| &r.id), ["r2"]);
+        assert_eq!(ids(&diff.events_added, |e| &e.id), ["e2"]);
+        assert_eq!(ids(&diff.events_edited, |e| &e.id), ["e1"]);
+        assert_eq!(ids(&diff.sacrament_attendance, |s| &s.id), ["s2"]);
+        // p3 is only in the older snapshot, the renamed p4 only in the newer
+        assert_eq!(diff.participants("e2"), "p3, Dee");
+        assert_eq!(diff.participants("e9"), "nobody");
+        assert_eq!(diff.name("x1"), "x1");
+    }
+
+    #[test]
+    fn null_and_missing_area_are_the_same() {
+        let from = commands(json!({ "contacts": [contact("p1")] }));
+        let to =
+            commands(json!({ "contacts": [with(contact("p1"), json!({ "prosAreaId": null }))] }));
+        assert!(AreaDiff::between(&from, &to).is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+// Jackson Coxson
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::diff::format_millis;
+use crate::timeline::{CommandsResponse, Contact};
+
+/// Pairs scoring at least this are reported unless asked otherwise.
+pub const DEFAULT_MIN_SCORE: u32 = 50;
+
+const NAME_POINTS: f64 = 40.0;
+const PHONE_POINTS: u32 = 35;
+const HOUSEHOLD_POINTS: u32 = 15;
+const CREATED_WEEK_POINTS: u32 = 10;
+const CREATED_MONTH_POINTS: u32 = 5;
+/// Names less alike than this aren't the same person, whatever else matches.
+/// "john smith" and "jane smith" are 0.7.
+const MIN_NAME_SIMILARITY: f64 = 0.8;
+/// How much of each word in a name is used to pick who gets compared
+const NAME_BLOCK_LEN: usize = 3;
+const MILLIS_IN_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// A contact as the duplicate check sees it.
+struct Candidate<'a> {
+    contact: &'a Contact,
+    area: &'a str,
+    /// Lowercased letters and digits of "first last"
+    name: String,
+    /// The same with first and last swapped, for names entered backwards
+    swapped: String,
+    phones: Vec<String>,
+}
+
+impl<'a> Candidate<'a> {
+    fn new(contact: &'a Contact, area: &'a str) -> Self {
+        let first = normalize_name(
+            contact
+                .first_name
+                .as_ref()
+                .and_then(Option::as_deref)
+                .unwrap_or_default(),
+        );
+        let last = normalize_name(
+            contact
+                .last_name
+                .as_ref()
+                .and_then(Option::as_deref)
+                .unwrap_or_default(),
+        );
+        Candidate {
+            contact,
+            area,
+            name: format!("{first} {last}").trim().to_string(),
+            swapped: format!("{last} {first}").trim().to_string(),
+            phones: [&contact.phone_mobile, &contact.phone_work]
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(|p| normalize_phone(p))
+                .collect(),
+        }
+    }
+}
+
+/// Two contacts that are probably the same person.
+pub struct Match<'a> {
+    pub a: &'a Contact,
+    pub b: &'a Contact,
+    /// Out of 100
+    pub score: u32,
+    pub reasons: Vec<String>,
+}
+
+/// Contacts that probably all belong to one person, and why.
+pub struct DuplicateGroup<'a> {
+    /// Each contact with the name of the area it's in
+    pub contacts: Vec<(&'a Contact, &'a str)>,
+    pub matches: Vec<Match<'a>>,
+}
+
+impl DuplicateGroup<'_> {
+    pub fn best_score(&self) -> u32 {
+        self.matches.iter().map(|m| m.score).max().unwrap_or(0)
+    }
+}
+
+/// Finds contacts within and across areas that look like the same person
+/// entered more than once. Copies with the same id are the same contact seen
+/// from two areas, not a duplicate, and only the first is looked at.
+pub fn find(areas: &[CommandsResponse], min_score: u32) -> Vec<DuplicateGroup<'_>> {
+    let mut ids = HashSet::new();
+    let candidates = areas
+        .iter()
+        .flat_map(|area| area.contacts.iter().map(move |c| (c, &area.pros_area_name)))
+        .filter(|(contact, _)| ids.insert(&contact.id))
+        .map(|(contact, area)| Candidate::new(contact, area))
+        .collect::<Vec<Candidate>>();
+
+    // Indices into `candidates` of every pair scoring high enough
+    let mut pairs = Vec::new();
+    for (i, j) in compared(&candidates) {
+        let (score, reasons) = score(&candidates[i], &candidates[j]);
+        if score >= min_score {
+            pairs.push((i, j, score, reasons));
+        }
+    }
+
+    group(&candidates, pairs)
+}
+
+/// The pairs worth scoring, in order: contacts sharing a phone number, a
+/// household or the start of a word in their name. Everything else can't
+/// score, and comparing every pair is too slow for a whole mission.
+fn compared(candidates: &[Candidate]) -> BTreeSet<(usize, usize)> {
+    let mut blocks: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, c) in candidates.iter().enumerate() {
+        let mut keys = c
+            .name
+            .split(' ')
+            .filter(|w| !w.is_empty())
+            .map(|w| {
+                format!(
+                    "name {}",
+                    w.chars().take(NAME_BLOCK_LEN).collect::<String>()
+                )
+            })
+            .chain(c.phones.iter().map(|p| format!("phone {p}")))
+            .collect::<Vec<String>>();
+        if !c.contact.household_id.is_empty() {
+            keys.push(format!("household {}", c.contact.household_id));
+        }
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            blocks.entry(key).or_default().push(i);
+        }
+    }
+
+    let mut pairs = BTreeSet::new();
+    for block in blocks.values() {
+        for (n, &i) in block.iter().enumerate() {
+            for &j in &block[n + 1..] {
+                pairs.insert((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+/// How alike two contacts are, out of 100, and what made them alike. Without
+/// alike names it's 0, since a family shares a phone and a household.
+fn score(a: &Candidate, b: &Candidate) -> (u32, Vec<String>) {
+    if a.name.is_empty() || b.name.is_empty() {
+        return (0, Vec::new());
+    }
+    let similarity = similarity(&a.name, &b.name).max(similarity(&a.swapped, &b.name));
+    if similarity < MIN_NAME_SIMILARITY {
+        return (0, Vec::new());
+    }
+
+    let mut score = (similarity * NAME_POINTS).round() as u32;
+    let mut reasons = vec![if similarity == 1.0 {
+        "same name".to_string()
+    } else {
+        format!("names {:.0}% alike", similarity * 100.0)
+    }];
+    if a.phones.iter().any(|p| b.phones.contains(p)) {
+        score += PHONE_POINTS;
+        reasons.push("same phone".to_string());
+    }
+    if !a.contact.household_id.is_empty() && a.contact.household_id == b.contact.household_id {
+        score += HOUSEHOLD_POINTS;
+        reasons.push("same household".to_string());
+    }
+    let apart = a.contact.create_date.abs_diff(b.contact.create_date) / MILLIS_IN_DAY;
+    if apart <= 7 {
+        score += CREATED_WEEK_POINTS;
+        reasons.push(format!("created {apart} days apart"));
+    } else if apart <= 30 {
+        score += CREATED_MONTH_POINTS;
+        reasons.push(format!("created {apart} days apart"));
+    }
+
+    (score, reasons)
+}
+
+/// Joins matching pairs into groups, so three copies of someone are one
+/// group. Best matches first.
+fn group<'a>(
+    candidates: &[Candidate<'a>],
+    pairs: Vec<(usize, usize, u32, Vec<String>)>,
+) -> Vec<DuplicateGroup<'a>> {
+    // Union-find over candidate indices
+    let mut parent = (0..candidates.len()).collect::<Vec<usize>>();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for (a, b, _, _) in &pairs {
+        let (a, b) = (root(&mut parent, *a), root(&mut parent, *b));
+        parent[a.max(b)] = a.min(b);
+    }
+
+    let mut groups: Vec<(usize, DuplicateGroup)> = Vec::new();
+    for (a, b, score, reasons) in pairs {
+        let r = root(&mut parent, a);
+        let i = match groups.iter().position(|(root, _)| *root == r) {
+            Some(i) => i,
+            None => {
+                groups.push((
+                    r,
+                    DuplicateGroup {
+                        contacts: Vec::new(),
+                        matches: Vec::new(),
+                    },
+                ));
+                groups.len() - 1
+            }
+        };
+        let group = &mut groups[i].1;
+        let (a, b) = (&candidates[a], &candidates[b]);
+        for c in [a, b] {
+            if !group
+                .contacts
+                .iter()
+                .any(|(x, _)| std::ptr::eq(*x, c.contact))
+            {
+                group.contacts.push((c.contact, c.area));
+            }
+        }
+        group.matches.push(Match {
+            a: a.contact,
+            b: b.contact,
+            score,
+            reasons,
+        });
+    }
+
+    let mut groups = groups.into_iter().map(|(_, g)| g).collect::<Vec<_>>();
+    groups.sort_by_key(|g| std::cmp::Reverse(g.best_score()));
+    groups
+}
+
+pub fn print(groups: &[DuplicateGroup]) {
+    println!("{} groups of probable duplicates", groups.len());
+    for group in groups {
+        println!();
+        println!("score {}:", group.best_score());
+        for (c, area) in &group.contacts {
+            let phone = [&c.phone_mobile, &c.phone_work]
+                .into_iter()
+                .flatten()
+                .flatten()
+                .next()
+                .map(|p| format!(" {p}"))
+                .unwrap_or_default();
+            println!(
+                "  {} ({}, {area}){phone}, created {}, {}",
+                c.display_name(),
+                c.status,
+                format_millis(c.create_date),
+                c.id
+            );
+        }
+        for m in &group.matches {
+            println!(
+                "    {} and {}: {}, {}",
+                m.a.display_name(),
+                m.b.display_name(),
+                m.score,
+                m.reasons.join(", ")
+            );
+        }
+    }
+}
+
+/// Lowercase letters and digits, with everything else as single spaces:
+/// "  Mary-Jane " and "mary jane" are the same.
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_lowercase().next().unwrap_or(c)
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Just the digits, without a leading US country code, so "+1 (801) 555-1234"
+/// and "801.555.1234" match. Too short to be a phone number is `None`.
+fn normalize_phone(phone: &str) -> Option<String> {
+    let digits = phone
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    let digits = match digits.strip_prefix('1') {
+        Some(rest) if digits.len() == 11 => rest.to_string(),
+        _ => digits,
+    };
+    (digits.len() >= 7).then_some(digits)
+}
+
+/// 1 for the same string down to 0 for nothing in common, by edit distance.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a = a.chars().collect::<Vec<char>>();
+    let b = b.chars().collect::<Vec<char>>();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + (ca != cb) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    1.0 - previous[b.len()] as f64 / longest as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{commands, contact, with};
+    use serde_json::{json, Value};
+
+    const DAY: u64 = MILLIS_IN_DAY;
+
+    fn named(
+        id: &str,
+        first: &str,
+        last: &str,
+        household: &str,
+        phone: Option<&str>,
+        created: u64,
+    ) -> Value {
+        with(
+            contact(id),
+            json!({
+                "first": first,
+                "last": last,
+                "householdId": household,
+                "phoneMobile": phone,
+                "createDate": created,
+            }),
+        )
+    }
+
+    fn parse(contact: Value) -> Contact {
+        serde_json::from_value(contact).unwrap()
+    }
+
+    fn area(name: &str, contacts: Vec<Value>) -> CommandsResponse {
+        commands(json!({ "contacts": contacts, "prosAreaName": name }))
+    }
+
+    fn score_of(a: Value, b: Value) -> u32 {
+        let (a, b) = (parse(a), parse(b));
+        score(&Candidate::new(&a, "A"), &Candidate::new(&b, "B")).0
+    }
+
+    #[test]
+    fn normalize_phone_keeps_digits() {
+        assert_eq!(
+            normalize_phone("+1 (801) 555-1234").as_deref(),
+            Some("8015551234")
+        );
+        assert_eq!(
+            normalize_phone("801.555.1234").as_deref(),
+            Some("8015551234")
+        );
+        assert_eq!(normalize_phone("555-1234").as_deref(), Some("5551234"));
+        // Only an 11 digit number has a country code to drop
+        assert_eq!(normalize_phone("1555123").as_deref(), Some("1555123"));
+        assert_eq!(normalize_phone("ext 12"), None);
+        assert_eq!(normalize_phone(""), None);
+    }
+
+    #[test]
+    fn normalize_name_ignores_case_and_punctuation() {
+        assert_eq!(normalize_name("  Mary-Jane "), "mary jane");
+        assert_eq!(normalize_name("O'Brien"), "o brien");
+        assert_eq!(normalize_name("JOSÉ"), "josé");
+        assert_eq!(normalize_name(" -- "), "");
+    }
+
+    #[test]
+    fn similarity_is_by_edit_distance() {
+        assert_eq!(similarity("john", "john"), 1.0);
+        assert_eq!(similarity("", ""), 1.0);
+        assert_eq!(similarity("abc", "xyz"), 0.0);
+        assert_eq!(similarity("john smith", "jon smith"), 0.9);
+        assert_eq!(similarity("john smith", "jane smith"), 0.7);
+        assert_eq!(similarity("jon", ""), 0.0);
+    }
+
+    #[test]
+    fn score_adds_up_what_matches() {
+        let a = named("a", "John", "Smith", "h1", Some("801-555-1234"), 0);
+        let same = named("b", "john", "SMITH", "h1", Some("+1 801 555 1234"), 3 * DAY);
+        assert_eq!(score_of(a.clone(), same), 100);
+        let swapped = named("b", "Smith", "John", "h2", None, 20 * DAY);
+        assert_eq!(score_of(a.clone(), swapped), 45);
+        let typo = named("b", "Jon", "Smith", "h2", None, 90 * DAY);
+        assert_eq!(score_of(a, typo), 36);
+    }
+
+    #[test]
+    fn family_members_dont_match_without_alike_names() {
+        let john = named("a", "John", "Smith", "h1", Some("8015551234"), 0);
+        let jane = named("b", "Jane", "Smith", "h1", Some("8015551234"), 0);
+        assert_eq!(score_of(john.clone(), jane), 0);
+        let unnamed = named("b", "", "", "h1", Some("8015551234"), 0);
+        assert_eq!(score_of(john, unnamed), 0);
+    }
+
+    #[test]
+    fn find_reports_shared_contacts_once() {
+        let john = named("a", "John", "Smith", "h1", Some("8015551234"), 0);
+        let jon = named("b", "Jon", "Smith", "h2", Some("8015551234"), DAY);
+        let areas = [
+            area("One", vec![john.clone(), jon.clone()]),
+            area("Two", vec![john, jon]),
+        ];
+        let groups = find(&areas, DEFAULT_MIN_SCORE);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].matches.len(), 1);
+        let areas = groups[0]
+            .contacts
+            .iter()
+            .map(|(c, area)| (c.id.as_str(), *area))
+            .collect::<Vec<_>>();
+        assert_eq!(areas, vec![("a", "One"), ("b", "One")]);
+    }
+
+    #[test]
+    fn find_only_compares_contacts_sharing_something() {
+        let areas = [area(
+            "One",
+            vec![
+                named("a", "John", "Smith", "h1", None, 0),
+                named("b", "Ann", "Brown", "h2", None, 0),
+                named("c", "Jphn", "Smith", "h3", None, 0),
+            ],
+        )];
+        let candidates = areas[0]
+            .contacts
+            .iter()
+            .map(|c| Candidate::new(c, "One"))
+            .collect::<Vec<Candidate>>();
+        assert_eq!(compared(&candidates), BTreeSet::from([(0, 2)]));
+    }
+}
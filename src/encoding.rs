@@ -0,0 +1,194 @@
+// Jackson Coxson
+
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING, CONTENT_TYPE};
+
+use crate::error::ApiError;
+
+/// Every gzip stream starts with these two bytes.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Undoes whatever compression a response body came with, in memory.
+///
+/// Areabook gzips its JSON, but doesn't always say so, so bodies without a
+/// `Content-Encoding` are still gunzipped if they start with the gzip magic
+/// bytes. HTML is rejected here so an error page never ends up in the cache.
+pub fn decode_body(headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>, ApiError> {
+    let encodings = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|e| e.trim().to_ascii_lowercase())
+        .filter(|e| !e.is_empty() && e != "identity")
+        .collect::<Vec<String>>();
+
+    let mut data = body.to_vec();
+    // Listed in the order they were applied
+    for encoding in encodings.iter().rev() {
+        data = decode(encoding, &data)?;
+    }
+    if encodings.is_empty() && data.starts_with(&GZIP_MAGIC) {
+        data = decode("gzip", &data)?;
+    }
+
+    let is_html = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"))
+        || data.trim_ascii_start().starts_with(b"<");
+    if is_html {
+        return Err(ApiError::ErrorPage(page_title(&data)));
+    }
+    Ok(data)
+}
+
+fn decode(encoding: &str, data: &[u8]) -> Result<Vec<u8>, ApiError> {
+    let mut out = Vec::new();
+    let res = match encoding {
+        "gzip" | "x-gzip" => GzDecoder::new(data).read_to_end(&mut out),
+        // Supposed to be zlib wrapped, but some servers send it raw
+        "deflate" => ZlibDecoder::new(data).read_to_end(&mut out).or_else(|_| {
+            out.clear();
+            DeflateDecoder::new(data).read_to_end(&mut out)
+        }),
+        "br" => brotli::Decompressor::new(data, 4096).read_to_end(&mut out),
+        _ => {
+            return Err(ApiError::Decompress {
+                encoding: encoding.to_string(),
+                source: std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "unsupported content encoding",
+                ),
+            })
+        }
+    };
+    res.map(|_| out).map_err(|source| ApiError::Decompress {
+        encoding: encoding.to_string(),
+        source,
+    })
+}
+
+/// The `<title>` of an HTML page, which is usually enough to tell a login
+/// redirect from a maintenance page.
+fn page_title(html: &[u8]) -> Option<String> {
+    let html = String::from_utf8_lossy(html);
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = html[start..end].trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use reqwest::header::HeaderValue;
+    use std::io::Write;
+
+    const JSON: &[u8] = br#"{"contacts":[]}"#;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn raw_deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+        encoder.write_all(data).unwrap();
+        drop(encoder);
+        out
+    }
+
+    fn headers(pairs: &[(reqwest::header::HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn encoded(encoding: &'static str) -> HeaderMap {
+        headers(&[(CONTENT_ENCODING, encoding)])
+    }
+
+    #[test]
+    fn each_encoding_decodes() {
+        for (encoding, body) in [
+            ("gzip", gzip(JSON)),
+            ("x-gzip", gzip(JSON)),
+            ("deflate", zlib(JSON)),
+            ("deflate", raw_deflate(JSON)),
+            ("br", brotli(JSON)),
+            ("identity", JSON.to_vec()),
+        ] {
+            let decoded = decode_body(&encoded(encoding), &body).unwrap();
+            assert_eq!(decoded, JSON, "{encoding}");
+        }
+    }
+
+    #[test]
+    fn unlabeled_gzip_is_still_gunzipped() {
+        assert_eq!(decode_body(&HeaderMap::new(), &gzip(JSON)).unwrap(), JSON);
+        assert_eq!(decode_body(&HeaderMap::new(), JSON).unwrap(), JSON);
+    }
+
+    #[test]
+    fn stacked_encodings_come_off_in_reverse() {
+        let body = brotli(&gzip(JSON));
+        assert_eq!(decode_body(&encoded("gzip, br"), &body).unwrap(), JSON);
+        let split = headers(&[(CONTENT_ENCODING, "gzip"), (CONTENT_ENCODING, "BR")]);
+        assert_eq!(decode_body(&split, &body).unwrap(), JSON);
+    }
+
+    #[test]
+    fn unknown_and_corrupt_encodings_fail() {
+        assert!(matches!(
+            decode_body(&encoded("zstd"), JSON),
+            Err(ApiError::Decompress { encoding, .. }) if encoding == "zstd"
+        ));
+        assert!(matches!(
+            decode_body(&encoded("gzip"), JSON),
+            Err(ApiError::Decompress { .. })
+        ));
+    }
+
+    #[test]
+    fn html_is_rejected_with_its_title() {
+        let page =
+            b"\n  <!DOCTYPE html><html><head><TITLE> Down for Maintenance </TITLE></head></html>";
+        assert!(matches!(
+            decode_body(&HeaderMap::new(), page),
+            Err(ApiError::ErrorPage(Some(title))) if title == "Down for Maintenance"
+        ));
+        assert!(matches!(
+            decode_body(&encoded("gzip"), &gzip(b"<html></html>")),
+            Err(ApiError::ErrorPage(None))
+        ));
+        // The content type alone is enough
+        let html = headers(&[(CONTENT_TYPE, "text/html; charset=utf-8")]);
+        assert!(matches!(
+            decode_body(&html, b"Sign in"),
+            Err(ApiError::ErrorPage(None))
+        ));
+    }
+}
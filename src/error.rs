@@ -0,0 +1,116 @@
+// Jackson Coxson
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+/// Everything that can go wrong talking to Areabook or reading what it sent us.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request never got a response (DNS, TLS, timeout, dropped connection...)
+    Network(reqwest::Error),
+    /// 401 or 403, almost always a wrong or expired CHURCH_USERNAME/CHURCH_PASSWORD
+    Unauthorized(reqwest::StatusCode),
+    /// The body couldn't be decompressed with the encoding it claimed (or looked like)
+    Decompress {
+        encoding: String,
+        source: std::io::Error,
+    },
+    /// Any other status outside 2xx, after retries ran out
+    Status(reqwest::StatusCode),
+    /// An HTML page where JSON should be, with its title if it has one
+    ErrorPage(Option<String>),
+    /// Replaying a cassette that has no response for this request
+    NotRecorded(String),
+    /// The JSON didn't match our structs. `path` is where it broke, like `contacts[3].status`
+    Schema {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// Reading or writing something under `cache/`
+    Cache(std::io::Error),
+    /// The cache is encrypted and the passphrase is missing or wrong
+    Encryption(&'static str),
+}
+
+impl ApiError {
+    /// Process exit code for this kind of failure, so scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ApiError::Network(_) => 2,
+            ApiError::Unauthorized(_) => 3,
+            ApiError::Decompress { .. } => 4,
+            ApiError::Schema { .. } => 5,
+            ApiError::Cache(_) => 6,
+            ApiError::Encryption(_) => 7,
+            ApiError::Status(_) => 8,
+            ApiError::ErrorPage(_) => 9,
+            ApiError::NotRecorded(_) => 10,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Network(e) => write!(f, "network error: {e}"),
+            ApiError::Unauthorized(status) => write!(
+                f,
+                "server returned {status}, check CHURCH_USERNAME and CHURCH_PASSWORD"
+            ),
+            ApiError::Decompress { encoding, source } => {
+                write!(f, "couldn't decode {encoding} response body: {source}")
+            }
+            ApiError::Status(status) => write!(f, "server returned {status}"),
+            ApiError::ErrorPage(Some(title)) => {
+                write!(f, "server sent an HTML page instead of JSON: {title}")
+            }
+            ApiError::ErrorPage(None) => write!(f, "server sent an HTML page instead of JSON"),
+            ApiError::NotRecorded(request) => {
+                write!(f, "the cassette has no response for {request}")
+            }
+            ApiError::Schema { path, source } => {
+                write!(f, "response doesn't match the schema at `{path}`: {source}")
+            }
+            ApiError::Cache(e) => write!(f, "cache error: {e}"),
+            ApiError::Encryption(reason) => write!(f, "can't decrypt the cache: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Network(e) => Some(e),
+            ApiError::Unauthorized(_) => None,
+            ApiError::Decompress { source, .. } => Some(source),
+            ApiError::Status(_) => None,
+            ApiError::ErrorPage(_) => None,
+            ApiError::NotRecorded(_) => None,
+            ApiError::Schema { source, .. } => Some(source),
+            ApiError::Cache(e) => Some(e),
+            ApiError::Encryption(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(value: reqwest::Error) -> Self {
+        ApiError::Network(value)
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(value: std::io::Error) -> Self {
+        ApiError::Cache(value)
+    }
+}
+
+/// Parses JSON, keeping track of the field path if it fails.
+pub fn parse_json<T: DeserializeOwned>(data: impl AsRef<[u8]>) -> Result<T, ApiError> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(data.as_ref());
+    serde_path_to_error::deserialize(deserializer).map_err(|e| ApiError::Schema {
+        path: e.path().to_string(),
+        source: e.into_inner(),
+    })
+}
@@ -0,0 +1,277 @@
+// Jackson Coxson
+
+use serde_json::Value;
+
+/// Names that can't be used as field names as they are: every strict and
+/// reserved keyword, 2024 edition included.
+const KEYWORDS: [&str; 52] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield", "Self",
+];
+
+/// The shape of some JSON, merged across every value seen in the same spot.
+#[derive(Debug, Clone, PartialEq)]
+enum Shape {
+    /// Only seen in empty arrays so far
+    Unknown,
+    Null,
+    Bool,
+    /// An integer, and whether any were negative
+    Int(bool),
+    Float,
+    String,
+    Array(Box<Shape>),
+    /// Fields in the order they were first seen, and whether every value had them
+    Object(Vec<(String, Shape, bool)>),
+    Optional(Box<Shape>),
+    /// Disagreeing types, left as `serde_json::Value`
+    Any,
+}
+
+impl Shape {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => Shape::Null,
+            Value::Bool(_) => Shape::Bool,
+            Value::Number(n) if n.is_u64() => Shape::Int(false),
+            Value::Number(n) if n.is_i64() => Shape::Int(true),
+            Value::Number(_) => Shape::Float,
+            Value::String(_) => Shape::String,
+            Value::Array(items) => Shape::Array(Box::new(
+                items
+                    .iter()
+                    .map(Shape::of)
+                    .fold(Shape::Unknown, Shape::merge),
+            )),
+            Value::Object(map) => Shape::Object(
+                map.iter()
+                    .map(|(k, v)| (k.clone(), Shape::of(v), true))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn merge(self, other: Shape) -> Shape {
+        match (self, other) {
+            (Shape::Unknown, x) | (x, Shape::Unknown) => x,
+            (Shape::Null, Shape::Null) => Shape::Null,
+            (Shape::Null, x) | (x, Shape::Null) => x.optional(),
+            (Shape::Optional(a), b) | (b, Shape::Optional(a)) => a.merge(b).optional(),
+            (Shape::Int(a), Shape::Int(b)) => Shape::Int(a || b),
+            (Shape::Int(_) | Shape::Float, Shape::Int(_) | Shape::Float) => Shape::Float,
+            (Shape::Array(a), Shape::Array(b)) => Shape::Array(Box::new(a.merge(*b))),
+            (Shape::Object(a), Shape::Object(mut b)) => {
+                let mut fields = Vec::new();
+                for (key, shape, required) in a {
+                    match b.iter().position(|(k, _, _)| *k == key) {
+                        Some(i) => {
+                            let (_, other, other_required) = b.remove(i);
+                            fields.push((key, shape.merge(other), required && other_required));
+                        }
+                        None => fields.push((key, shape, false)),
+                    }
+                }
+                fields.extend(b.into_iter().map(|(k, s, _)| (k, s, false)));
+                Shape::Object(fields)
+            }
+            (a, b) if a == b => a,
+            _ => Shape::Any,
+        }
+    }
+
+    fn optional(self) -> Shape {
+        match self {
+            Shape::Optional(_) | Shape::Null => self,
+            x => Shape::Optional(Box::new(x)),
+        }
+    }
+}
+
+/// Draft Rust structs for a JSON response, in the style of the models in
+/// `timeline.rs`. Nested structs come before the ones using them and
+/// `root_name` comes last. Fields missing from some array elements or null in
+/// some become `Option`, disagreeing types become `serde_json::Value`.
+pub fn skeleton(root_name: &str, value: &Value) -> String {
+    let mut structs = Vec::new();
+    let root = rust_type(&Shape::of(value), root_name, &mut structs);
+    if structs.is_empty() {
+        return format!("pub type {root_name} = {root};\n");
+    }
+    structs.join("\n")
+}
+
+/// The Rust type for a shape, writing out any structs it needs.
+fn rust_type(shape: &Shape, name: &str, structs: &mut Vec<String>) -> String {
+    match shape {
+        Shape::Unknown | Shape::Any => "serde_json::Value".to_string(),
+        Shape::Null => "Option<serde_json::Value>".to_string(),
+        Shape::Bool => "bool".to_string(),
+        Shape::Int(false) => "u64".to_string(),
+        Shape::Int(true) => "i64".to_string(),
+        Shape::Float => "f64".to_string(),
+        Shape::String => "String".to_string(),
+        Shape::Array(item) => format!("Vec<{}>", rust_type(item, &singular(name), structs)),
+        Shape::Optional(inner) => format!("Option<{}>", rust_type(inner, name, structs)),
+        Shape::Object(fields) => {
+            let name = unique_name(name, structs);
+            let mut out = format!("#[derive(Deserialize, Debug)]\npub struct {name} {{\n");
+            for (key, shape, required) in fields {
+                let mut ty = rust_type(shape, &pascal_case(key), structs);
+                if !required && !ty.starts_with("Option<") {
+                    ty = format!("Option<{ty}>");
+                }
+                let field = snake_case(key);
+                if field != *key {
+                    out.push_str(&format!("    #[serde(rename = \"{key}\")]\n"));
+                }
+                out.push_str(&format!("    pub {field}: {ty},\n"));
+            }
+            out.push_str("}\n");
+            structs.push(out);
+            name
+        }
+    }
+}
+
+/// `name`, or `name2`, `name3`... if a struct already has it.
+fn unique_name(name: &str, structs: &[String]) -> String {
+    let taken = |n: &str| {
+        structs
+            .iter()
+            .any(|s| s.contains(&format!("pub struct {n} {{")))
+    };
+    if !taken(name) {
+        return name.to_string();
+    }
+    (2..)
+        .map(|i| format!("{name}{i}"))
+        .find(|n| !taken(n))
+        .expect("some number is free")
+}
+
+/// `personDrops` to `PersonDrops`, `user/details` to `UserDetails`.
+pub fn pascal_case(s: &str) -> String {
+    s.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            let mut chars = w.chars();
+            let first = chars.next().expect("words aren't empty");
+            first.to_ascii_uppercase().to_string() + chars.as_str()
+        })
+        .collect::<String>()
+}
+
+/// `prosAreaId` to `pros_area_id` and `lessonYN` to `lesson_yn`, keeping away
+/// from keywords.
+fn snake_case(s: &str) -> String {
+    let chars = s.chars().collect::<Vec<char>>();
+    let mut out = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_ascii_uppercase() {
+            // A new word starts after a lowercase letter, or at the last capital of a run
+            let prev = i.checked_sub(1).map(|j| chars[j]);
+            let next = chars.get(i + 1);
+            let starts_word = prev.is_some_and(|p| p.is_ascii_lowercase() || p.is_ascii_digit())
+                || (prev.is_some_and(|p| p.is_ascii_uppercase())
+                    && next.is_some_and(|n| n.is_ascii_lowercase()));
+            if starts_word && !out.ends_with('_') {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            out.push(c);
+        } else if !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    // Not `r#`, which doesn't work for `self`, `super` and `crate`
+    if KEYWORDS.contains(&out.as_str()) {
+        out.push('_');
+    }
+    out
+}
+
+/// A best guess at one element of a list, `Contacts` to `Contact`.
+fn singular(name: &str) -> String {
+    if let Some(stem) = name.strip_suffix("ies") {
+        format!("{stem}y")
+    } else if name.ends_with("ss") {
+        name.to_string()
+    } else {
+        name.strip_suffix('s').unwrap_or(name).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snake_case_splits_words() {
+        assert_eq!(snake_case("prosAreaId"), "pros_area_id");
+        assert_eq!(snake_case("lessonYN"), "lesson_yn");
+        assert_eq!(snake_case("HTTPStatus"), "http_status");
+        assert_eq!(snake_case("area2Name"), "area2_name");
+        assert_eq!(snake_case("found-by"), "found_by");
+        assert_eq!(snake_case("2fa"), "_2fa");
+        assert_eq!(snake_case(""), "_");
+    }
+
+    #[test]
+    fn snake_case_avoids_keywords() {
+        for keyword in [
+            "type", "async", "dyn", "try", "yield", "self", "super", "crate",
+        ] {
+            assert_eq!(snake_case(keyword), format!("{keyword}_"));
+        }
+        assert_eq!(snake_case("Self"), "self_");
+        assert_eq!(snake_case("typeId"), "type_id");
+    }
+
+    #[test]
+    fn skeleton_merges_array_elements() {
+        let value = serde_json::json!({
+            "areaId": 5,
+            "contacts": [
+                {"id": "a", "age": 3, "note": null},
+                {"id": "b", "age": -1.5, "note": "hi", "extra": true}
+            ],
+            "type": "x"
+        });
+        assert_eq!(
+            skeleton("Response", &value),
+            concat!(
+                "#[derive(Deserialize, Debug)]\n",
+                "pub struct Contact {\n",
+                "    pub age: f64,\n",
+                "    pub id: String,\n",
+                "    pub note: Option<String>,\n",
+                "    pub extra: Option<bool>,\n",
+                "}\n",
+                "\n",
+                "#[derive(Deserialize, Debug)]\n",
+                "pub struct Response {\n",
+                "    #[serde(rename = \"areaId\")]\n",
+                "    pub area_id: u64,\n",
+                "    pub contacts: Vec<Contact>,\n",
+                "    #[serde(rename = \"type\")]\n",
+                "    pub type_: String,\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn skeleton_of_a_bare_value() {
+        assert_eq!(
+            skeleton("Ids", &serde_json::json!([1, 2])),
+            "pub type Ids = Vec<u64>;\n"
+        );
+    }
+}
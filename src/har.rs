@@ -0,0 +1,314 @@
+// Jackson Coxson
+
+use std::path::Path;
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::cache::{Cache, CacheMeta, Resource};
+use crate::encoding::decode_body;
+use crate::error::{parse_json, ApiError};
+use crate::info::MissionaryInfo;
+use crate::kic::KeyIndicatorsResponse;
+use crate::timeline::CommandsResponse;
+
+// Just the parts of a HAR export we need, see http://www.softwareishard.com/blog/har-12-spec/
+
+#[derive(Deserialize, Debug)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarEntry {
+    #[serde(rename = "startedDateTime")]
+    started_date_time: String,
+    request: HarRequest,
+    response: HarResponse,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarRequest {
+    url: String,
+    headers: Vec<HarHeader>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarResponse {
+    status: u16,
+    headers: Vec<HarHeader>,
+    content: HarContent,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarContent {
+    text: Option<String>,
+    /// `base64` for binary bodies, missing when `text` is the body itself
+    encoding: Option<String>,
+}
+
+impl HarRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+}
+
+/// What came out of importing a capture.
+#[derive(Default)]
+pub struct HarImport {
+    pub imported: Vec<Resource>,
+    /// Areabook exchanges that couldn't be used, and why
+    pub skipped: Vec<(String, String)>,
+}
+
+impl HarImport {
+    pub fn print(&self) {
+        for resource in &self.imported {
+            println!("Imported {resource}");
+        }
+        for (url, reason) in &self.skipped {
+            println!("Skipped {url}: {reason}");
+        }
+        println!(
+            "Imported {} responses, skipped {}",
+            self.imported.len(),
+            self.skipped.len()
+        );
+    }
+}
+
+/// Reads an HTTP Toolkit (or any other) HAR export and writes every
+/// `user/details`, `key-indicators` and `commands` response in it to the cache.
+/// Entries are applied oldest first, so a delta sync lands on top of the full
+/// sync captured before it.
+pub async fn import(cache: &Cache, path: &Path) -> Result<HarImport, ApiError> {
+    let har: Har = parse_json(fs::read(path).await?)?;
+    let mut entries = har.log.entries;
+    entries.sort_by_key(|e| started_millis(e).unwrap_or(0));
+
+    let mut report = HarImport::default();
+    for entry in &entries {
+        let Some(endpoint) = endpoint(&entry.request.url) else {
+            continue;
+        };
+        if let Err(reason) = import_entry(cache, endpoint, entry, &mut report).await {
+            report
+                .skipped
+                .push((entry.request.url.clone(), reason.to_string()));
+        }
+    }
+    Ok(report)
+}
+
+/// Which Areabook endpoint a URL is, if any.
+pub fn endpoint(url: &str) -> Option<&'static str> {
+    let path = url.split(['?', '#']).next()?.trim_end_matches('/');
+    ["user/details", "key-indicators", "commands"]
+        .into_iter()
+        .find(|e| path.ends_with(&format!("/{e}")))
+}
+
+fn started_millis(entry: &HarEntry) -> Option<u64> {
+    DateTime::parse_from_rfc3339(&entry.started_date_time)
+        .ok()
+        .map(|t| t.timestamp_millis() as u64)
+}
+
+async fn import_entry(
+    cache: &Cache,
+    endpoint: &str,
+    entry: &HarEntry,
+    report: &mut HarImport,
+) -> Result<(), ApiError> {
+    let response = &entry.response;
+    if !(200..300).contains(&response.status) {
+        return Err(ApiError::Status(
+            reqwest::StatusCode::from_u16(response.status)
+                .unwrap_or(reqwest::StatusCode::INTERNAL_SERVER_ERROR),
+        ));
+    }
+    let data = body(response)?;
+
+    let meta = CacheMeta {
+        fetched_at: started_millis(entry).unwrap_or_else(|| Utc::now().timestamp_millis() as u64),
+        client_version: entry
+            .request
+            .header("client-version")
+            .unwrap_or("unknown")
+            .to_string(),
+        source_url: entry.request.url.clone(),
+    };
+
+    let resource = match endpoint {
+        "user/details" => {
+            parse_json::<MissionaryInfo>(&data)?;
+            cache.write(&Resource::UserDetails, &data, &meta).await?;
+            Resource::UserDetails
+        }
+        "key-indicators" => {
+            parse_json::<KeyIndicatorsResponse>(&data)?;
+            cache.write(&Resource::KeyIndicators, &data, &meta).await?;
+            Resource::KeyIndicators
+        }
+        _ => {
+            let Some(area) = entry.request.header("prosAreaId") else {
+                report.skipped.push((
+                    entry.request.url.clone(),
+                    "no prosAreaId header".to_string(),
+                ));
+                return Ok(());
+            };
+            let resource = Resource::Commands(area.to_string());
+            let mut res: CommandsResponse = parse_json(&data)?;
+
+            if entry.request.header("syncStrategy") == Some("D") {
+                match cache.read(&resource).await? {
+                    Some(cached) => {
+                        let mut cached: CommandsResponse = parse_json(&cached)?;
+                        cached.merge(res);
+                        res = cached;
+                    }
+                    None => {
+                        report.skipped.push((
+                            entry.request.url.clone(),
+                            format!("delta sync for area {area} without a full sync before it"),
+                        ));
+                        return Ok(());
+                    }
+                }
+            }
+
+            let data = serde_json::to_vec(&res).expect("CommandsResponse always serializes");
+            cache.write(&resource, &data, &meta).await?;
+            resource
+        }
+    };
+    report.imported.push(resource);
+    Ok(())
+}
+
+/// The decompressed response body. Some tools store the body already decoded
+/// but keep the original `Content-Encoding`, so if decoding by the headers
+/// fails it's tried again going only by the bytes.
+fn body(response: &HarResponse) -> Result<Vec<u8>, ApiError> {
+    let text = response
+        .content
+        .text
+        .as_deref()
+        .ok_or(ApiError::Decompress {
+            encoding: "identity".to_string(),
+            source: std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the capture has no response body",
+            ),
+        })?;
+    let raw = match response.content.encoding.as_deref() {
+        Some("base64") => base64::engine::general_purpose::STANDARD
+            .decode(text)
+            .map_err(|e| ApiError::Decompress {
+                encoding: "base64".to_string(),
+                source: std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            })?,
+        _ => text.as_bytes().to_vec(),
+    };
+
+    let mut headers = HeaderMap::new();
+    for h in &response.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(h.name.as_bytes()),
+            HeaderValue::from_str(&h.value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+
+    match decode_body(&headers, &raw) {
+        Err(ApiError::Decompress { .. }) => decode_body(&HeaderMap::new(), &raw),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn import_applies_the_delta_on_top_of_the_full_sync() {
+        let dir = std::env::temp_dir().join(format!("presi-test-{}", uuid::Uuid::new_v4()));
+        let cache = Cache::new(&dir);
+        let report = import(
+            &cache,
+            Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/capture.har")),
+        )
+        .await
+        .unwrap();
+        let area = Resource::Commands("1".to_string());
+        let data = cache.read(&area).await.unwrap();
+        let meta = cache.meta(&area).await;
+        let other = cache
+            .read(&Resource::Commands("2".to_string()))
+            .await
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(report.imported, [area.clone(), area]);
+        let skipped = report
+            .skipped
+            .iter()
+            .map(|(url, reason)| (endpoint(url).unwrap(), reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            skipped,
+            [
+                ("key-indicators", "server returned 503 Service Unavailable"),
+                ("commands", "no prosAreaId header"),
+                (
+                    "commands",
+                    "delta sync for area 2 without a full sync before it"
+                ),
+            ]
+        );
+        assert!(other.is_none());
+
+        let res: CommandsResponse = parse_json(data.unwrap()).unwrap();
+        let names = res
+            .contacts
+            .iter()
+            .map(|c| c.display_name())
+            .collect::<Vec<String>>();
+        assert_eq!(names, ["Ann", "Benjamin", "Cal"]);
+        assert_eq!(res.pros_area_name, "Test Area");
+        let meta = meta.unwrap();
+        assert_eq!(meta.fetched_at, 1_709_485_500_000);
+        assert_eq!(meta.client_version, "5.2.1");
+    }
+
+    #[test]
+    fn endpoint_ignores_query_and_other_hosts() {
+        let base = "https://areabook.churchofjesuschrist.org/ws/areabook/v5.2";
+        assert_eq!(endpoint(&format!("{base}/commands?x=1")), Some("commands"));
+        assert_eq!(
+            endpoint(&format!("{base}/user/details/")),
+            Some("user/details")
+        );
+        assert_eq!(endpoint(&format!("{base}/commands/extra")), None);
+        assert_eq!(endpoint("https://example.com/analytics"), None);
+    }
+}
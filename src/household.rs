@@ -0,0 +1,145 @@
+// Jackson Coxson
+
+use std::collections::{HashMap, HashSet};
+
+use crate::codes::ContactStatus;
+use crate::timeline::{Person, TimelineEntry};
+
+/// Everyone sharing a `householdId`, so a family taught together can be
+/// looked at as one.
+pub struct Household<'a> {
+    pub id: &'a str,
+    /// The area of the first member seen
+    pub area: &'a str,
+    pub members: Vec<&'a Person>,
+}
+
+impl<'a> Household<'a> {
+    /// Groups people into households, in the order each household first
+    /// shows up.
+    pub fn group(people: &'a [Person]) -> Vec<Household<'a>> {
+        let mut households: Vec<Household> = Vec::new();
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        for person in people {
+            match index.get(person.household_id.as_str()) {
+                Some(&i) => households[i].members.push(person),
+                None => {
+                    let household = Household {
+                        id: &person.household_id,
+                        area: &person.area,
+                        members: vec![person],
+                    };
+                    index.insert(household.id, households.len());
+                    households.push(household);
+                }
+            }
+        }
+        households
+    }
+
+    /// Person ids of everyone in the household.
+    pub fn member_ids(&self) -> Vec<&'a str> {
+        self.members.iter().map(|p| p.id.as_str()).collect()
+    }
+
+    /// Every member's timeline merged in time order. An event with several
+    /// members in it only shows up once.
+    pub fn timeline(&self) -> Vec<&'a TimelineEntry> {
+        let mut seen = HashSet::new();
+        let mut timeline = self
+            .members
+            .iter()
+            .flat_map(|p| p.timeline.iter())
+            .filter(|e| e.event().is_none_or(|event| seen.insert(&event.id)))
+            .collect::<Vec<&TimelineEntry>>();
+        timeline.sort_by_key(|e| e.time);
+        timeline
+    }
+
+    /// The status of whoever is furthest along, so a family where one person
+    /// is progressing counts as progressing.
+    pub fn status(&self) -> ContactStatus {
+        self.members
+            .iter()
+            .map(|p| p.status)
+            .max_by_key(|s| progress(*s))
+            .unwrap_or(ContactStatus::Unknown(0))
+    }
+}
+
+/// How far along a status is, for picking a household's.
+fn progress(status: ContactStatus) -> u8 {
+    match status {
+        ContactStatus::Unknown(_) => 0,
+        ContactStatus::NotProgressing => 1,
+        ContactStatus::New => 2,
+        ContactStatus::BeingTaught => 3,
+        ContactStatus::Progressing => 4,
+        ContactStatus::Member => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{commands, contact, event, with};
+    use crate::timeline::TimelineEventKind;
+    use chrono_tz::Tz;
+    use serde_json::json;
+
+    /// p1 and p3 in h1 taught together once, p2 alone in h2, p1 dropped later.
+    fn people() -> Vec<Person> {
+        let person = |id, household, status| {
+            with(
+                contact(id),
+                json!({ "householdId": household, "status": status }),
+            )
+        };
+        let res = commands(json!({
+            "personEvents": [
+                { "id": "pe1", "personId": "p1", "eventId": "e1" },
+                { "id": "pe2", "personId": "p3", "eventId": "e1" },
+                { "id": "pe3", "personId": "p2", "eventId": "e2" },
+            ],
+            "events": [event("e1", 2000), event("e2", 1000)],
+            "personDrops": [
+                { "id": "d1", "personId": "p1", "dropDate": 3000, "status": 1 },
+            ],
+            "contacts": [person("p1", "h1", 1), person("p2", "h2", 10), person("p3", "h1", 20)],
+        }));
+        let mut people = res.into_people(Tz::UTC);
+        people.sort_by(|a, b| a.id.cmp(&b.id));
+        people
+    }
+
+    #[test]
+    fn group_keeps_first_seen_order() {
+        let people = people();
+        let households = Household::group(&people);
+        let ids = households
+            .iter()
+            .map(|h| (h.id, h.member_ids()))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![("h1", vec!["p1", "p3"]), ("h2", vec!["p2"])]);
+        assert_eq!(households[0].area, "Test Area");
+    }
+
+    #[test]
+    fn shared_events_show_up_once() {
+        let people = people();
+        let households = Household::group(&people);
+        let timeline = households[0].timeline();
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(timeline[0].event().map(|e| e.id.as_str()), Some("e1"));
+        assert!(matches!(timeline[1].kind, TimelineEventKind::Drop(_)));
+        assert!(timeline.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn status_is_whoever_is_furthest_along() {
+        let people = people();
+        let households = Household::group(&people);
+        assert_eq!(households[0].status(), ContactStatus::Progressing);
+        assert_eq!(households[1].status(), ContactStatus::BeingTaught);
+    }
+}
@@ -1,9 +1,10 @@
-use serde::Deserialize;
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-#[derive(Deserialize, Debug)]
+use crate::cache::{Cache, Resource};
+use crate::error::{parse_json, ApiError};
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Calling {
     #[serde(rename = "cmisId")]
     pub cmis_id: u64,
@@ -13,9 +14,11 @@ pub struct Calling {
     pub position_name: String,
     #[serde(rename = "unitId")]
     pub unit_id: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct MissionaryInfo {
     #[serde(rename = "missionaryId")]
     pub missionary_id: u64,
@@ -35,7 +38,12 @@ pub struct MissionaryInfo {
     pub gender: String,
     #[serde(rename = "churchArea")]
     pub church_area: String,
-    pub callings: Option<Vec<Calling>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub callings: Option<Option<Vec<Calling>>>,
     #[serde(rename = "missionId")]
     pub mission_id: u64,
     #[serde(rename = "missionName")]
@@ -54,22 +62,24 @@ pub struct MissionaryInfo {
     pub pros_area_id: u64,
     #[serde(rename = "prosAreaName")]
     pub pros_area_name: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl MissionaryInfo {
-    /// Reads the missionary information from a JSON file at the given path.
-    pub fn from_cache<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let mut file = File::open(path)?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)?;
-        Ok(content.try_into()?)
+    /// Reads the missionary information from the cached user details.
+    pub async fn from_cache(cache: &Cache) -> Result<Self, ApiError> {
+        match cache.read(&Resource::UserDetails).await? {
+            Some(content) => content.try_into(),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+        }
     }
 }
 
 impl TryFrom<String> for MissionaryInfo {
-    type Error = std::io::Error;
+    type Error = ApiError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        Ok(serde_json::from_str(&value)?)
+        parse_json(&value)
     }
 }
@@ -1,7 +1,9 @@
 // Jackson Coxson
 
 use serde::Deserialize;
-use tokio::fs;
+
+use crate::cache::{Cache, Resource};
+use crate::error::{parse_json, ApiError};
 
 #[derive(Deserialize, Debug)]
 pub struct KeyIndicator {
@@ -15,9 +17,12 @@ pub struct KeyIndicatorsResponse {
     pub area_key_indicators: Vec<KeyIndicator>,
 }
 
-pub async fn extract_pros_area_ids(path: &str) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
-    let data = fs::read_to_string(path).await?;
-    let key_indicators: KeyIndicatorsResponse = serde_json::from_str(&data)?;
+pub async fn extract_pros_area_ids(cache: &Cache) -> Result<Vec<u64>, ApiError> {
+    let data = match cache.read(&Resource::KeyIndicators).await? {
+        Some(data) => data,
+        None => return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+    };
+    let key_indicators: KeyIndicatorsResponse = parse_json(&data)?;
 
     let pros_area_ids = key_indicators
         .area_key_indicators
@@ -1,59 +1,235 @@
 // Jackson Coxson
 
-use std::u64;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
 
 use api::MissionaryApi;
-use chrono::{Duration, Utc};
+use cache::{Cache, Resource};
+use cassette::CassetteMode;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use crypto::CacheKey;
+use error::ApiError;
 use info::MissionaryInfo;
+use profile::DeviceProfile;
+use retry::RetryPolicy;
+use snapshot::{RetentionPolicy, SnapshotStore};
+use sync::SyncState;
 
 mod api;
+mod cache;
+mod cassette;
+mod codes;
+mod crypto;
+mod diff;
+mod duplicates;
+mod encoding;
+mod error;
+mod explore;
+mod har;
+mod household;
 mod info;
 mod kic;
+mod mission;
+mod profile;
+mod referral;
+mod retry;
+mod schema;
+mod snapshot;
 mod stats;
+mod sync;
 mod timeline;
 
+const USAGE: &str = "Usage:
+  presi                        stats from the cache, fetching anything stale
+  presi sync                   sync every area with the server, then stats
+  presi cache status           show what's cached and how old it is
+  presi cache refresh <area>   download an area from scratch
+  presi cache purge            delete the whole cache
+  presi cache encrypt          encrypt an existing plaintext cache and history
+  presi import-har <file>      fill the cache from an HTTP Toolkit HAR export
+  presi explore <path> [Name:Value...]  call any endpoint, print it and a struct draft
+  presi schema-check [cassette]  compare cached (or recorded) responses to our structs
+  presi history <area> [date]  list an area's snapshots, or show it as of YYYY-MM-DD
+  presi diff <from> <to> [area]  what changed between two YYYY-MM-DD dates
+  presi referrals [person]     who found whom, for everyone or one person (id or name)
+  presi referrals --format dot|graphml  the referral graph for GraphViz or yEd/Gephi
+  presi duplicates [min score] contacts that look like the same person entered twice";
+
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
 
-    let username = std::env::var("CHURCH_USERNAME").expect("No username in env var!");
-    let password = std::env::var("CHURCH_PASSWORD").expect("No password in env var!");
+    let cache =
+        Cache::new(std::env::var("AREABOOK_CACHE_DIR").unwrap_or_else(|_| "cache".to_string()));
+    let mut retention = RetentionPolicy::default();
+    if let Some(days) = env_parse("AREABOOK_KEEP_ALL_DAYS") {
+        retention.keep_all_days = days;
+    }
+    if let Some(days) = env_parse("AREABOOK_KEEP_WEEKLY_DAYS") {
+        retention.keep_weekly_days = days;
+    }
+    let history = SnapshotStore::new(
+        std::env::var("AREABOOK_HISTORY_DIR").unwrap_or_else(|_| "history".to_string()),
+    )
+    .with_retention(retention);
 
-    let api = MissionaryApi::new(username, password);
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
 
-    println!("Getting user info...");
-    let info = match MissionaryInfo::from_cache("cache/info.json") {
-        Ok(i) => i,
-        Err(_) => {
-            println!("Getting info from church servers");
-            api.fetch_user_details("cache/info.json")
+    // Everything but these reads or writes personal data, which is always encrypted
+    let (cache, history) = match args.as_slice() {
+        ["cache", "status"] | ["cache", "purge"] => (cache, history),
+        _ => {
+            let key = Arc::new(unlock(&cache, &history).await);
+            (cache.with_key(key.clone()), history.with_key(key))
+        }
+    };
+
+    match args.as_slice() {
+        // `sync` asks the server for everything that changed since the last run,
+        // otherwise cached areas are used until their TTL runs out
+        [] => run(cache, history, false).await,
+        ["sync"] => run(cache, history, true).await,
+        ["cache", "status"] => cache
+            .print_status()
+            .await
+            .unwrap_or_else(|e| bail("Failed to read the cache", e)),
+        ["cache", "refresh", area] => match area.parse::<u64>() {
+            // It ends up in a path, so nothing but a prosAreaId
+            Ok(area) => refresh_area(cache, history, &area.to_string()).await,
+            Err(_) => {
+                eprintln!("{area} isn't a prosAreaId\n{USAGE}");
+                std::process::exit(1);
+            }
+        },
+        ["import-har", path] => {
+            har::import(&cache, Path::new(path))
                 .await
-                .expect("Failed to get user info");
-            MissionaryInfo::from_cache("cache/info.json").expect("Failed to read info.json")
+                .unwrap_or_else(|e| bail(&format!("Failed to import {path}"), e))
+                .print();
+        }
+        ["explore", path, headers @ ..] => explore_endpoint(cache, history, path, headers).await,
+        ["schema-check"] => schema_check_cache(&cache).await,
+        ["schema-check", path] => schema_check_cassette(&cache, path).await,
+        ["history", area] => list_history(&history, area).await,
+        ["history", area, date] => show_history(&history, area, date).await,
+        ["diff", from, to] => print_diff(&history, from, to, None).await,
+        ["diff", from, to, area] => print_diff(&history, from, to, Some(area)).await,
+        ["referrals"] => print_referrals(&cache, None).await,
+        ["referrals", "--format", format @ ("dot" | "graphml")] => {
+            export_referrals(&cache, format).await
         }
+        ["referrals", person] => print_referrals(&cache, Some(person)).await,
+        ["duplicates"] => print_duplicates(&cache, duplicates::DEFAULT_MIN_SCORE).await,
+        ["duplicates", min_score] => match min_score.parse() {
+            Ok(min_score) => print_duplicates(&cache, min_score).await,
+            Err(_) => {
+                eprintln!("{USAGE}");
+                std::process::exit(1);
+            }
+        },
+        ["cache", "purge"] => {
+            cache
+                .purge()
+                .await
+                .unwrap_or_else(|e| bail("Failed to purge the cache", e));
+            println!("Deleted {}", cache.root().display());
+        }
+        ["cache", "encrypt"] => encrypt_cache(&cache, &history).await,
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The cache passphrase from AREABOOK_CACHE_PASSPHRASE, or asked for on the
+/// terminal. `confirm` asks twice, for when a new passphrase is being set.
+fn cache_key(confirm: bool) -> Result<CacheKey, ApiError> {
+    if let Ok(passphrase) = std::env::var("AREABOOK_CACHE_PASSPHRASE") {
+        return Ok(CacheKey::new(passphrase));
+    }
+
+    let prompt = |text| {
+        rpassword::prompt_password(text).map_err(|_| {
+            ApiError::Encryption("no terminal to ask on, set AREABOOK_CACHE_PASSPHRASE")
+        })
     };
-    println!(
-        "Logged in as {} {} in the {}",
-        info.first, info.last, info.mission_name
-    );
+    let passphrase = prompt("Cache passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(ApiError::Encryption("the passphrase is empty"));
+    }
+    if confirm && prompt("Again: ")? != passphrase {
+        return Err(ApiError::Encryption("the passphrases don't match"));
+    }
+    Ok(CacheKey::new(passphrase))
+}
 
-    // Fetch or load key indicators
-    println!("Fetching key indicators...");
-    let kics_path = "cache/kics.json";
-    if std::path::Path::new(kics_path).exists() {
-        println!("Key indicators found in cache. Loading...");
-    } else {
-        println!("Key indicators not found in cache. Fetching from API...");
-        api.fetch_key_indicators(&info, kics_path)
+/// Gets the passphrase and checks it against the cache and history before
+/// anything is written with it. A new passphrase is asked for twice.
+async fn unlock(cache: &Cache, history: &SnapshotStore) -> CacheKey {
+    let mut roots = Vec::new();
+    for root in [cache.root(), history.root()] {
+        let marked = tokio::fs::try_exists(root.join(crypto::MARKER))
+            .await
+            .unwrap_or(false);
+        roots.push((marked, root));
+    }
+    // Checked ones first, so a wrong passphrase doesn't mark the other
+    roots.sort_by_key(|(marked, _)| !marked);
+    let new = !roots[0].0;
+
+    let key = cache_key(new).unwrap_or_else(|e| bail("No passphrase", e));
+    for (_, root) in roots {
+        crypto::unlock(root, &key)
             .await
-            .expect("Failed to fetch key indicators");
+            .unwrap_or_else(|e| bail(&format!("Failed to unlock {}", root.display()), e));
     }
+    key
+}
+
+/// Encrypts everything already in the cache and history.
+async fn encrypt_cache(cache: &Cache, history: &SnapshotStore) {
+    let resources = cache
+        .encrypt_all()
+        .await
+        .unwrap_or_else(|e| bail("Failed to encrypt the cache", e));
+    let snapshots = history
+        .encrypt_all()
+        .await
+        .unwrap_or_else(|e| bail("Failed to encrypt the history", e));
+    println!("Encrypted {resources} cached responses and {snapshots} snapshots");
+}
+
+/// Fetches everything and prints the stats.
+async fn run(cache: Cache, history: SnapshotStore, refresh: bool) {
+    // Without credentials, go with whatever is cached, such as an imported HAR
+    if !refresh
+        && std::env::var("CHURCH_USERNAME").is_err()
+        && std::env::var("AREABOOK_REPLAY").is_err()
+    {
+        return run_offline(cache).await;
+    }
+
+    let api = build_api(cache, history).await;
+    let info = load_info(&api).await;
+
+    // Fetch or load key indicators
+    println!("Fetching key indicators...");
+    refresh_if_stale(
+        api.cache(),
+        &Resource::KeyIndicators,
+        api.fetch_key_indicators(&info),
+    )
+    .await;
 
     // Extract prosAreaIds
     println!("Extracting prosAreaIds from kics.json...");
-    let pros_area_ids = kic::extract_pros_area_ids(kics_path)
+    let pros_area_ids = kic::extract_pros_area_ids(api.cache())
         .await
-        .expect("Failed to extract prosAreaIds");
+        .unwrap_or_else(|e| bail("Failed to extract prosAreaIds", e));
 
     println!(
         "Found {} prosAreaIds: {:?}",
@@ -61,23 +237,46 @@ async fn main() {
         pros_area_ids
     );
 
-    let mut people = Vec::new();
+    let concurrency = env_parse("AREABOOK_CONCURRENCY").unwrap_or(mission::DEFAULT_CONCURRENCY);
+    let mut fetch = mission::fetch_mission(&api, &info, &pros_area_ids, refresh, concurrency).await;
+    fetch.print_summary();
 
-    for area in pros_area_ids {
-        match api.fetch_commands(&area.to_string(), &info).await {
-            Ok(mut p) => {
-                people.append(&mut p);
-            }
-            Err(e) => {
-                println!("Failed to fetch area {area}: {e:?}");
-            }
-        }
+    // A bad password fails every area the same way
+    if let Some(i) = fetch
+        .failures
+        .iter()
+        .position(|(_, e)| matches!(e, ApiError::Unauthorized(_)))
+    {
+        let (area, e) = fetch.failures.swap_remove(i);
+        bail(&format!("Failed to fetch area {area}"), e);
     }
+    finish(&api).await;
+    print_stats(fetch.people);
+}
+
+/// Prints the stats from the cache alone, never contacting the server.
+async fn run_offline(cache: Cache) {
+    println!("No CHURCH_USERNAME, using the cache as is");
+    let info = MissionaryInfo::from_cache(&cache)
+        .await
+        .unwrap_or_else(|e| bail("Failed to read info.json", e));
+    println!(
+        "Logged in as {} {} in the {}",
+        info.first, info.last, info.mission_name
+    );
 
+    let pros_area_ids = kic::extract_pros_area_ids(&cache)
+        .await
+        .unwrap_or_else(|e| bail("Failed to extract prosAreaIds", e));
+    let fetch = mission::load_mission(&cache, &pros_area_ids, timezone()).await;
+    fetch.print_summary();
+    print_stats(fetch.people);
+}
+
+fn print_stats(people: Vec<timeline::Person>) {
     println!("Collected {} people", people.len());
 
     let now = Utc::now();
-    let now_timestamp = now.timestamp() as u64 * 1000;
 
     // Subtract 6 months (approx. 6 * 30 days = 180 days)
     let six_months_ago = now - Duration::days(180);
@@ -86,5 +285,443 @@ async fn main() {
     let twelve_ago = now - Duration::days(365);
     let twelve_ago_timestamp = twelve_ago.timestamp() as u64 * 1000;
     println!("LAST 6 MONTHS STATS");
-    stats::print_stats(people, twelve_ago_timestamp, six_months_ago_timestamp);
+    let counting = match std::env::var("AREABOOK_COUNT_CONTACTS") {
+        Ok(v) => v.parse().unwrap_or_else(|e| {
+            eprintln!("AREABOOK_COUNT_CONTACTS: {e}");
+            std::process::exit(1)
+        }),
+        Err(_) => stats::Counting::default(),
+    };
+    let grouping = match std::env::var("AREABOOK_STATS_BY") {
+        Ok(v) => v.parse().unwrap_or_else(|e| {
+            eprintln!("AREABOOK_STATS_BY: {e}");
+            std::process::exit(1)
+        }),
+        Err(_) => stats::Grouping::default(),
+    };
+    stats::print_stats(
+        people,
+        twelve_ago_timestamp,
+        six_months_ago_timestamp,
+        counting,
+        grouping,
+    );
+}
+
+/// Throws away an area's cache and sync state and downloads it again.
+async fn refresh_area(cache: Cache, history: SnapshotStore, area: &str) {
+    let api = build_api(cache, history).await;
+    let info = load_info(&api).await;
+
+    let resource = Resource::Commands(area.to_string());
+    api.cache()
+        .invalidate(&resource)
+        .await
+        .unwrap_or_else(|e| bail("Failed to clear the cache", e));
+    SyncState::remove(api.cache(), area)
+        .await
+        .unwrap_or_else(|e| bail("Failed to clear the sync state", e));
+
+    let res = api
+        .sync_commands(area, &info)
+        .await
+        .unwrap_or_else(|e| bail(&format!("Failed to fetch area {area}"), e));
+    println!(
+        "Refreshed {} ({area}): {} contacts, {} events",
+        res.pros_area_name,
+        res.contacts.len(),
+        res.events.len()
+    );
+    finish(&api).await;
+}
+
+/// Calls an endpoint we have no model for, then prints the response and a
+/// draft of the structs to deserialize it with.
+async fn explore_endpoint(cache: Cache, history: SnapshotStore, path: &str, headers: &[&str]) {
+    let headers = headers
+        .iter()
+        .map(|h| match h.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => {
+                eprintln!("{USAGE}");
+                std::process::exit(1);
+            }
+        })
+        .collect::<Vec<(&str, &str)>>();
+    // Full paths copied out of HTTP Toolkit work too
+    let path = path
+        .split_once("/ws/areabook/v5.2/")
+        .map(|(_, p)| p)
+        .unwrap_or(path);
+
+    let api = build_api(cache, history).await;
+    let data = api
+        .raw_get(path, &headers)
+        .await
+        .unwrap_or_else(|e| bail(&format!("Failed to fetch {path}"), e));
+    finish(&api).await;
+
+    match serde_json::from_slice::<serde_json::Value>(&data) {
+        Ok(json) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&json).expect("Values always serialize")
+            );
+            let name = format!("{}Response", explore::pascal_case(path));
+            println!("\n{}", explore::skeleton(&name, &json));
+        }
+        Err(e) => {
+            println!("{}", String::from_utf8_lossy(&data));
+            eprintln!("Not JSON ({e}), no struct draft");
+        }
+    }
+}
+
+/// Compares everything in the cache to the structs it gets parsed with.
+async fn schema_check_cache(cache: &Cache) {
+    let resources = cache
+        .resources()
+        .await
+        .unwrap_or_else(|e| bail("Failed to read the cache", e));
+    let mut reports = Vec::new();
+    for resource in resources {
+        let data = cache
+            .read(&resource)
+            .await
+            .unwrap_or_else(|e| bail("Failed to read the cache", e))
+            .unwrap_or_default();
+        reports.push(
+            schema::SchemaReport::check(resource.to_string(), &resource, data.as_bytes())
+                .unwrap_or_else(|e| bail(&format!("Failed to read {resource}"), e)),
+        );
+    }
+    print_schema_reports(&reports);
+}
+
+/// Compares the responses in a cassette to our structs. Unlike the cache,
+/// these are exactly what the server sent.
+async fn schema_check_cassette(cache: &Cache, path: &str) {
+    let cassette = cassette::Cassette::load(path, cache.key().map(|k| k.as_ref()))
+        .await
+        .unwrap_or_else(|e| bail(&format!("Failed to read {path}"), e));
+    let mut reports = Vec::new();
+    for interaction in &cassette.interactions {
+        let request = &interaction.request;
+        let resource = match har::endpoint(&request.url) {
+            Some("user/details") => Resource::UserDetails,
+            Some("key-indicators") => Resource::KeyIndicators,
+            Some(_) => match request.headers.get("prosareaid") {
+                Some(area) => Resource::Commands(area.clone()),
+                None => continue,
+            },
+            None => continue,
+        };
+        let source = format!(
+            "{resource} ({})",
+            request
+                .headers
+                .get("syncstrategy")
+                .map_or("full", |s| match s.as_str() {
+                    "D" => "delta",
+                    _ => "full",
+                })
+        );
+
+        let report = interaction
+            .response
+            .parts()
+            .and_then(|(status, headers, body)| {
+                if !status.is_success() {
+                    return Err(ApiError::Status(status));
+                }
+                encoding::decode_body(&headers, &body)
+            })
+            .and_then(|data| schema::SchemaReport::check(source.clone(), &resource, &data));
+        match report {
+            Ok(report) => reports.push(report),
+            Err(e) => println!("{source}: skipped, {e}"),
+        }
+    }
+    print_schema_reports(&reports);
+}
+
+/// Prints the reports, exiting with the schema error code if any response
+/// wouldn't parse.
+fn print_schema_reports(reports: &[schema::SchemaReport]) {
+    for report in reports {
+        report.print();
+    }
+    let broken = reports.iter().filter(|r| r.breaks()).count();
+    if broken > 0 {
+        eprintln!("{broken} responses no longer parse");
+        std::process::exit(5);
+    }
+}
+
+/// Prints when each snapshot of an area was taken.
+async fn list_history(history: &SnapshotStore, area: &str) {
+    let taken = history
+        .list(area)
+        .await
+        .unwrap_or_else(|e| bail("Failed to read the history", e));
+    if taken.is_empty() {
+        println!("No snapshots of area {area}");
+    }
+    for t in taken {
+        println!("{}", t.format("%Y-%m-%d %H:%M:%S UTC"));
+    }
+}
+
+/// Prints what an area looked like at the end of the given day.
+async fn show_history(history: &SnapshotStore, area: &str, date: &str) {
+    let at = parse_date(date);
+    match history.load_as_of(area, at).await {
+        Ok(Some((taken, res))) => println!(
+            "{} ({area}) as of {}: {} contacts, {} events, {} drops, {} resets",
+            res.pros_area_name,
+            taken.format("%Y-%m-%d %H:%M:%S UTC"),
+            res.contacts.len(),
+            res.events.len(),
+            res.person_drops.len(),
+            res.person_resets.len()
+        ),
+        Ok(None) => println!("No snapshot of area {area} from on or before {date}"),
+        Err(e) => bail("Failed to read the history", e),
+    }
+}
+
+/// Prints what changed in each area between the snapshots in effect at the
+/// end of `from` and the end of `to`.
+async fn print_diff(history: &SnapshotStore, from: &str, to: &str, area: Option<&str>) {
+    let (from, to) = (parse_date(from), parse_date(to));
+    let areas = match area {
+        Some(area) => vec![area.to_string()],
+        None => history
+            .areas()
+            .await
+            .unwrap_or_else(|e| bail("Failed to read the history", e)),
+    };
+
+    for area in areas {
+        let load = |at| history.load_as_of(&area, at);
+        let (old, new) = match (load(from).await, load(to).await) {
+            (Ok(Some(old)), Ok(Some(new))) => (old, new),
+            (Err(e), _) | (_, Err(e)) => bail("Failed to read the history", e),
+            _ => {
+                println!("Area {area}: not enough snapshots to compare");
+                continue;
+            }
+        };
+
+        let diff = diff::AreaDiff::between(&old.1, &new.1);
+        println!(
+            "{} ({area}), {} to {}:",
+            new.1.pros_area_name,
+            old.0.format("%Y-%m-%d %H:%M"),
+            new.0.format("%Y-%m-%d %H:%M")
+        );
+        if diff.is_empty() {
+            println!("  no changes");
+        }
+        diff.print();
+    }
+}
+
+/// The referral graph of every cached area.
+async fn referral_graph(cache: &Cache) -> referral::ReferralGraph {
+    let areas = mission::load_commands(cache)
+        .await
+        .unwrap_or_else(|e| bail("Failed to read the cache", e));
+    referral::ReferralGraph::build(&areas)
+}
+
+async fn print_referrals(cache: &Cache, person: Option<&str>) {
+    let graph = referral_graph(cache).await;
+    match person {
+        None => graph.print_summary(),
+        Some(query) => match graph.find(query) {
+            Some(node) => graph.print_tree(&node.id),
+            None => {
+                eprintln!("No one called {query} in the cache");
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+/// Prints the referral graph in `format` so it can be piped to a file.
+async fn export_referrals(cache: &Cache, format: &str) {
+    let graph = referral_graph(cache).await;
+    match format {
+        "dot" => print!("{}", graph.to_dot()),
+        _ => print!("{}", graph.to_graphml()),
+    }
+}
+
+/// Lists contacts in every cached area that look like the same person.
+async fn print_duplicates(cache: &Cache, min_score: u32) {
+    let areas = mission::load_commands(cache)
+        .await
+        .unwrap_or_else(|e| bail("Failed to read the cache", e));
+    duplicates::print(&duplicates::find(&areas, min_score));
+}
+
+/// The end of a YYYY-MM-DD day, or the usage if it isn't one.
+fn parse_date(date: &str) -> DateTime<Utc> {
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(d) => d
+            .and_hms_opt(23, 59, 59)
+            .expect("23:59:59 is a valid time")
+            .and_utc(),
+        Err(_) => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Builds the API client from the environment.
+async fn build_api(cache: Cache, history: SnapshotStore) -> MissionaryApi {
+    // A replayed cassette has no credentials in it to check
+    let replay = std::env::var("AREABOOK_REPLAY").ok();
+    let (username, password) = match replay {
+        Some(_) => (
+            std::env::var("CHURCH_USERNAME").unwrap_or_default(),
+            std::env::var("CHURCH_PASSWORD").unwrap_or_default(),
+        ),
+        None => (
+            std::env::var("CHURCH_USERNAME").expect("No username in env var!"),
+            std::env::var("CHURCH_PASSWORD").expect("No password in env var!"),
+        ),
+    };
+
+    let profile_path =
+        std::env::var("AREABOOK_DEVICE_PROFILE").unwrap_or_else(|_| "device.json".to_string());
+    let profile = DeviceProfile::load(&profile_path)
+        .unwrap_or_else(|e| bail(&format!("Failed to read {profile_path}"), e));
+
+    let mut retry = RetryPolicy::default();
+    if let Some(max_retries) = env_parse("AREABOOK_MAX_RETRIES") {
+        retry.max_retries = max_retries;
+    }
+    let requests_per_second =
+        env_parse("AREABOOK_REQUESTS_PER_SECOND").unwrap_or(api::DEFAULT_REQUESTS_PER_SECOND);
+
+    // Replays start from an empty cache of their own, so every request goes to
+    // the cassette and the real cache and sync state are left alone
+    let cache = match (&replay, cache.key()) {
+        (Some(_), key) => {
+            let scratch = Cache::new(
+                std::env::temp_dir().join(format!("presi-replay-{}", uuid::Uuid::new_v4())),
+            );
+            match key {
+                Some(key) => scratch.with_key(key.clone()),
+                None => scratch,
+            }
+        }
+        (None, _) => cache,
+    };
+
+    let mut api = MissionaryApi::new(username, password)
+        .with_profile(profile)
+        .with_retry_policy(retry)
+        .with_rate_limit(requests_per_second)
+        .with_cache(cache)
+        .with_history(history)
+        .with_timezone(timezone());
+    if let Ok(base_url) = std::env::var("AREABOOK_BASE_URL") {
+        println!("Using Areabook server at {base_url}");
+        api = api.with_base_url(base_url);
+    }
+    if let Some(path) = replay {
+        println!("Replaying {path}");
+        let cassette = CassetteMode::replay(&path, api.cache().key().map(|k| k.as_ref()))
+            .await
+            .unwrap_or_else(|e| bail(&format!("Failed to read {path}"), e));
+        api = api.with_cassette(cassette);
+    } else if let Ok(path) = std::env::var("AREABOOK_RECORD") {
+        println!("Recording to {path}");
+        let key = api.cache().key().cloned();
+        api = api.with_cassette(CassetteMode::record(path, key));
+    }
+    api
+}
+
+/// Deletes the scratch cache a replay wrote to.
+async fn finish(api: &MissionaryApi) {
+    if api.is_replay() {
+        if let Err(e) = api.cache().purge().await {
+            eprintln!("Failed to clean up {}: {e}", api.cache().root().display());
+        }
+    }
+}
+
+/// Loads the logged in missionary, fetching it if the cache is stale.
+async fn load_info(api: &MissionaryApi) -> MissionaryInfo {
+    println!("Getting user info...");
+    refresh_if_stale(
+        api.cache(),
+        &Resource::UserDetails,
+        api.fetch_user_details(),
+    )
+    .await;
+    let info = MissionaryInfo::from_cache(api.cache())
+        .await
+        .unwrap_or_else(|e| bail("Failed to read info.json", e));
+    println!(
+        "Logged in as {} {} in the {}",
+        info.first, info.last, info.mission_name
+    );
+    info
+}
+
+/// Runs `fetch` if the cached copy of `resource` is missing or past its TTL.
+/// If fetching fails but there's an old copy, the old copy gets used.
+async fn refresh_if_stale(
+    cache: &Cache,
+    resource: &Resource,
+    fetch: impl Future<Output = Result<(), ApiError>>,
+) {
+    if cache.is_fresh(resource).await {
+        println!("Using cached {resource}");
+        return;
+    }
+
+    println!("Fetching {resource} from church servers...");
+    if let Err(e) = fetch.await {
+        let have_old_copy = matches!(cache.read(resource).await, Ok(Some(_)));
+        if matches!(e, ApiError::Unauthorized(_)) || !have_old_copy {
+            bail(&format!("Failed to fetch {resource}"), e);
+        }
+        println!("Failed to fetch {resource}, using the cached copy: {e}");
+    }
+}
+
+/// Prints the error and exits with its code, see `ApiError::exit_code`.
+fn bail(context: &str, e: ApiError) -> ! {
+    eprintln!("{context}: {e}");
+    std::process::exit(e.exit_code())
+}
+
+/// The mission's timezone from `AREABOOK_TIMEZONE`. Nothing Areabook sends
+/// says what it is, so unset is UTC with a warning.
+fn timezone() -> Tz {
+    match std::env::var("AREABOOK_TIMEZONE") {
+        Ok(name) => name.parse().unwrap_or_else(|_| {
+            eprintln!("AREABOOK_TIMEZONE: {name} isn't an IANA timezone like America/Denver");
+            std::process::exit(1)
+        }),
+        Err(_) => {
+            eprintln!(
+                "AREABOOK_TIMEZONE isn't set, reading sacrament dates as UTC. \
+                 Set it to the mission's timezone, like America/Denver."
+            );
+            Tz::UTC
+        }
+    }
+}
+
+/// Reads an optional number from the environment.
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
 }
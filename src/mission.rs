@@ -0,0 +1,308 @@
+// Jackson Coxson
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use chrono_tz::Tz;
+use futures::stream::{self, StreamExt};
+
+use crate::api::MissionaryApi;
+use crate::cache::{Cache, Resource};
+use crate::codes::OwnerStatus;
+use crate::error::{parse_json, ApiError};
+use crate::info::MissionaryInfo;
+use crate::timeline::{CommandsResponse, Person};
+
+/// How many areas are downloaded at once unless AREABOOK_CONCURRENCY says otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Everything collected from a mission-wide fetch.
+pub struct MissionFetch {
+    pub people: Vec<Person>,
+    /// How long each area that succeeded took
+    pub timings: Vec<(u64, Duration)>,
+    pub failures: Vec<(u64, ApiError)>,
+    pub elapsed: Duration,
+    /// How many people each area had that were kept under another area
+    pub merged: BTreeMap<u64, usize>,
+    /// Where each person is in `people`, the area that copy came from and how
+    /// strongly that area claims them
+    seen: HashMap<String, (usize, u64, u8)>,
+}
+
+impl MissionFetch {
+    fn new() -> Self {
+        Self {
+            people: Vec::new(),
+            timings: Vec::new(),
+            failures: Vec::new(),
+            elapsed: Duration::ZERO,
+            merged: BTreeMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Adds an area's people. Someone another area already had is merged
+    /// into one person, kept under whichever area owns them.
+    fn add(&mut self, area: u64, people: Vec<Person>) {
+        for person in people {
+            let claim = claim(&person, area);
+            let Some((i, kept_area, kept_claim)) = self.seen.get_mut(&person.id) else {
+                self.seen
+                    .insert(person.id.clone(), (self.people.len(), area, claim));
+                self.people.push(person);
+                continue;
+            };
+
+            // Ties go to the lower area id, so it doesn't matter which finished first
+            let kept = &mut self.people[*i];
+            if (claim, Reverse(area)) > (*kept_claim, Reverse(*kept_area)) {
+                let old = std::mem::replace(kept, person);
+                kept.merge(old);
+                *self.merged.entry(*kept_area).or_default() += 1;
+                (*kept_area, *kept_claim) = (area, claim);
+            } else {
+                kept.merge(person);
+                *self.merged.entry(area).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Fetched {} areas in {:.1}s, {} failed",
+            self.timings.len(),
+            self.elapsed.as_secs_f64(),
+            self.failures.len()
+        );
+        if let Some((area, slowest)) = self.timings.iter().max_by_key(|(_, t)| *t) {
+            println!("Slowest area was {area} at {:.1}s", slowest.as_secs_f64());
+        }
+        for (area, e) in &self.failures {
+            println!("  area {area}: {e}");
+        }
+        let merged = self.merged.values().sum::<usize>();
+        if merged > 0 {
+            println!("Merged {merged} duplicates of people in more than one area");
+            for (area, count) in &self.merged {
+                println!("  area {area}: {count} kept under another area");
+            }
+        }
+    }
+}
+
+/// How sure we are that `area` owns a person: its `prosAreaId` counts for
+/// more than the area's own `ownerStatus`.
+fn claim(person: &Person, area: u64) -> u8 {
+    let owns_by_id = person.area_id.map(u64::from) == Some(area);
+    let owns_by_status = person.owner_status == OwnerStatus::Owner;
+    owns_by_id as u8 * 2 + owns_by_status as u8
+}
+
+/// Fetches every area with at most `concurrency` requests in flight. With
+/// `refresh` each area is synced with the server, otherwise the cache is used
+/// where it exists.
+pub async fn fetch_mission(
+    api: &MissionaryApi,
+    info: &MissionaryInfo,
+    areas: &[u64],
+    refresh: bool,
+    concurrency: usize,
+) -> MissionFetch {
+    let started = Instant::now();
+    let total = areas.len();
+
+    let mut results = stream::iter(areas.iter().copied())
+        .map(|area| async move {
+            let area_started = Instant::now();
+            let res = if refresh {
+                api.sync_commands(&area.to_string(), info)
+                    .await
+                    .map(|r| r.into_people(api.timezone()))
+            } else {
+                api.fetch_commands(&area.to_string(), info).await
+            };
+            (area, area_started.elapsed(), res)
+        })
+        .buffer_unordered(concurrency.max(1));
+
+    let mut fetch = MissionFetch::new();
+    let mut done = 0;
+    while let Some((area, elapsed, res)) = results.next().await {
+        done += 1;
+        match res {
+            Ok(people) => {
+                println!(
+                    "[{done}/{total}] area {area}: {} people in {:.1}s",
+                    people.len(),
+                    elapsed.as_secs_f64()
+                );
+                fetch.add(area, people);
+                fetch.timings.push((area, elapsed));
+            }
+            Err(e) => {
+                println!("[{done}/{total}] area {area}: failed");
+                fetch.failures.push((area, e));
+            }
+        }
+    }
+
+    fetch.elapsed = started.elapsed();
+    fetch
+}
+
+/// Loads every area from the cache without contacting the server, however old
+/// the cached copies are. Areas that were never cached count as failures.
+pub async fn load_mission(cache: &Cache, areas: &[u64], timezone: Tz) -> MissionFetch {
+    let started = Instant::now();
+    let mut fetch = MissionFetch::new();
+
+    for &area in areas {
+        let area_started = Instant::now();
+        let res = match cache.read(&Resource::Commands(area.to_string())).await {
+            Ok(Some(data)) => {
+                parse_json::<CommandsResponse>(&data).map(|r| r.into_people(timezone))
+            }
+            Ok(None) => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(people) => {
+                println!("area {area}: {} people from the cache", people.len());
+                fetch.add(area, people);
+                fetch.timings.push((area, area_started.elapsed()));
+            }
+            Err(e) => fetch.failures.push((area, e)),
+        }
+    }
+
+    fetch.elapsed = started.elapsed();
+    fetch
+}
+
+/// Every cached area's commands as they are, for looking across the whole
+/// mission at once.
+pub async fn load_commands(cache: &Cache) -> Result<Vec<CommandsResponse>, ApiError> {
+    let mut areas = Vec::new();
+    for resource in cache.resources().await? {
+        if !matches!(resource, Resource::Commands(_)) {
+            continue;
+        }
+        if let Some(data) = cache.read(&resource).await? {
+            areas.push(parse_json(&data)?);
+        }
+    }
+    Ok(areas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{commands, contact, with};
+    use crate::timeline::TimelineEventKind;
+    use serde_json::{json, Value};
+
+    fn claimed(id: &str, pros_area_id: Option<u64>, owner_status: u32) -> Value {
+        with(
+            contact(id),
+            json!({ "prosAreaId": pros_area_id, "ownerStatus": owner_status }),
+        )
+    }
+
+    /// One area's people, with a reset for each of `resets` on p1.
+    fn area(name: &str, contacts: Vec<Value>, resets: &[&str]) -> Vec<Person> {
+        let resets = resets
+            .iter()
+            .enumerate()
+            .map(|(i, id)| json!({ "id": id, "personId": "p1", "resetDate": 1000 * i as u64 }))
+            .collect::<Vec<Value>>();
+        commands(json!({
+            "personResets": resets,
+            "contacts": contacts,
+            "prosAreaName": name,
+        }))
+        .into_people(Tz::UTC)
+    }
+
+    /// Adds the areas in order and returns where p1 ended up.
+    fn kept_in(areas: Vec<(u64, Vec<Person>)>) -> (String, MissionFetch) {
+        let mut fetch = MissionFetch::new();
+        for (id, people) in areas {
+            fetch.add(id, people);
+        }
+        assert_eq!(fetch.people.len(), 1);
+        (fetch.people[0].area.clone(), fetch)
+    }
+
+    #[test]
+    fn pros_area_id_beats_owner_status() {
+        let owner = || (10, area("Owner", vec![claimed("p1", Some(20), 1)], &[]));
+        let by_id = || (20, area("By id", vec![claimed("p1", Some(20), 2)], &[]));
+        assert_eq!(kept_in(vec![owner(), by_id()]).0, "By id");
+        assert_eq!(kept_in(vec![by_id(), owner()]).0, "By id");
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_area_id() {
+        let low = || (10, area("Low", vec![claimed("p1", None, 1)], &[]));
+        let high = || (20, area("High", vec![claimed("p1", None, 1)], &[]));
+        for order in [vec![low(), high()], vec![high(), low()]] {
+            let (kept, fetch) = kept_in(order);
+            assert_eq!(kept, "Low");
+            assert_eq!(fetch.merged, BTreeMap::from([(20, 1)]));
+        }
+    }
+
+    #[test]
+    fn merged_timelines_have_each_record_once() {
+        let (_, fetch) = kept_in(vec![
+            (10, area("A", vec![claimed("p1", Some(10), 1)], &["r1"])),
+            (20, area("B", vec![claimed("p1", None, 1)], &["r1", "r2"])),
+        ]);
+        let resets = fetch.people[0]
+            .timeline
+            .iter()
+            .filter_map(|e| match &e.kind {
+                TimelineEventKind::Reset(r) => Some(r.id.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<&str>>();
+        assert_eq!(resets, vec!["r1", "r2"]);
+    }
+
+    #[test]
+    fn merged_counts_are_per_area() {
+        let mut fetch = MissionFetch::new();
+        fetch.add(
+            30,
+            area(
+                "C",
+                vec![claimed("p1", None, 1), claimed("p2", None, 1)],
+                &[],
+            ),
+        );
+        fetch.add(10, area("A", vec![claimed("p1", Some(10), 2)], &[]));
+        fetch.add(
+            20,
+            area(
+                "B",
+                vec![claimed("p1", None, 1), claimed("p2", None, 1)],
+                &[],
+            ),
+        );
+
+        assert_eq!(fetch.people.len(), 2);
+        let area_of = |id: &str| {
+            fetch
+                .people
+                .iter()
+                .find(|p| p.id == id)
+                .map(|p| p.area.as_str())
+        };
+        assert_eq!(area_of("p1"), Some("A"));
+        assert_eq!(area_of("p2"), Some("B"));
+        // p1 left C and B, p2 left C
+        assert_eq!(fetch.merged, BTreeMap::from([(20, 1), (30, 2)]));
+    }
+}
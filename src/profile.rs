@@ -0,0 +1,67 @@
+// Jackson Coxson
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::error::{parse_json, ApiError};
+
+/// The device we pretend to be. Defaults to the Android emulator the API was
+/// captured from; override any field in `device.json` when the app updates.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DeviceProfile {
+    pub client_version: String,
+    pub build_number: String,
+    pub device_type: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub os_version: String,
+    pub api_level: u32,
+    pub device_id: String,
+    pub group_name: String,
+    pub language: String,
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        Self {
+            client_version: "6.20.0".to_string(),
+            build_number: "620037".to_string(),
+            device_type: "Android".to_string(),
+            manufacturer: "Android".to_string(),
+            model: "Android SDK built for x86_64".to_string(),
+            os_version: "14".to_string(),
+            api_level: 34,
+            device_id: "81c9cf2ff6e4bdee".to_string(),
+            group_name: "SRG".to_string(),
+            language: "en-US".to_string(),
+        }
+    }
+}
+
+impl DeviceProfile {
+    /// Reads a profile from a JSON file, falling back to the default if it doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ApiError> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => parse_json(&content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The User-Agent the app sends, e.g.
+    /// `Area Book 6.20.0 (620037). Android Android SDK built for x86_64. Android 14 (api 34) 81c9cf2ff6e4bdee`
+    pub fn user_agent(&self) -> String {
+        format!(
+            "Area Book {} ({}). {} {}. {} {} (api {}) {}",
+            self.client_version,
+            self.build_number,
+            self.manufacturer,
+            self.model,
+            self.device_type,
+            self.os_version,
+            self.api_level,
+            self.device_id
+        )
+    }
+}
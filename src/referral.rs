@@ -0,0 +1,417 @@
+// Jackson Coxson
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::codes::ContactStatus;
+use crate::timeline::CommandsResponse;
+
+/// Someone in the referral graph.
+pub struct Node {
+    pub id: String,
+    pub name: String,
+    /// `None` for someone who referred people but isn't a contact in any area
+    /// we have
+    pub status: Option<ContactStatus>,
+    pub area: Option<String>,
+    pub found_by: Option<String>,
+}
+
+impl Node {
+    pub fn is_baptized(&self) -> bool {
+        self.status == Some(ContactStatus::Member)
+    }
+
+    fn label(&self) -> String {
+        match (&self.status, &self.area) {
+            (Some(status), Some(area)) => format!("{} ({status}, {area})", self.name),
+            _ => format!("{} (not a contact)", self.name),
+        }
+    }
+}
+
+/// Who found or referred whom, from `foundByPersonId`, across every area.
+pub struct ReferralGraph {
+    /// By id, members included
+    nodes: BTreeMap<String, Node>,
+    /// Referrer id to the ids they referred
+    referred: BTreeMap<String, Vec<String>>,
+}
+
+/// How one referrer is doing.
+pub struct ReferrerStats<'a> {
+    pub node: &'a Node,
+    pub referred: usize,
+    pub baptized: usize,
+    /// The longest chain of referrals starting with them
+    pub depth: usize,
+}
+
+impl ReferralGraph {
+    /// Builds the graph from every area's commands. Someone in more than one
+    /// area is taken from the first.
+    pub fn build(areas: &[CommandsResponse]) -> Self {
+        let mut nodes = BTreeMap::new();
+        for area in areas {
+            for c in &area.contacts {
+                nodes.entry(c.id.clone()).or_insert_with(|| Node {
+                    id: c.id.clone(),
+                    name: c.display_name(),
+                    status: Some(c.status),
+                    area: Some(area.pros_area_name.clone()),
+                    found_by: c
+                        .found_by_person_id
+                        .clone()
+                        .flatten()
+                        .filter(|f| !f.is_empty()),
+                });
+            }
+        }
+
+        let mut referred: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for node in nodes.values() {
+            if let Some(found_by) = &node.found_by {
+                referred
+                    .entry(found_by.clone())
+                    .or_default()
+                    .push(node.id.clone());
+            }
+        }
+        // Referrers we only know by id
+        for id in referred.keys() {
+            nodes.entry(id.clone()).or_insert_with(|| Node {
+                id: id.clone(),
+                name: id.clone(),
+                status: None,
+                area: None,
+                found_by: None,
+            });
+        }
+
+        Self { nodes, referred }
+    }
+
+    /// Looks someone up by id, or else by name ignoring case.
+    pub fn find(&self, query: &str) -> Option<&Node> {
+        self.nodes.get(query).or_else(|| {
+            self.nodes
+                .values()
+                .find(|n| n.name.eq_ignore_ascii_case(query))
+        })
+    }
+
+    /// Everyone `id` found or referred directly.
+    pub fn referred_by(&self, id: &str) -> Vec<&Node> {
+        self.referred
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter_map(|r| self.nodes.get(r))
+            .collect()
+    }
+
+    /// The longest chain of referrals starting with `id`: 0 if they referred
+    /// nobody, 2 if someone they referred referred someone else.
+    pub fn chain_depth(&self, id: &str) -> usize {
+        self.depth(id, &mut HashSet::new())
+    }
+
+    fn depth<'a>(&'a self, id: &'a str, path: &mut HashSet<&'a str>) -> usize {
+        // Bad data could make a loop
+        if !path.insert(id) {
+            return 0;
+        }
+        let depth = self
+            .referred
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|r| 1 + self.depth(r, path))
+            .max()
+            .unwrap_or(0);
+        path.remove(id);
+        depth
+    }
+
+    /// How many people were referred by anyone, and how many of those are
+    /// members now.
+    pub fn baptism_rate(&self) -> (usize, usize) {
+        let referred = self.nodes.values().filter(|n| n.found_by.is_some());
+        let (total, baptized) = referred.fold((0, 0), |(total, baptized), n| {
+            (total + 1, baptized + n.is_baptized() as usize)
+        });
+        (total, baptized)
+    }
+
+    /// Everyone who referred someone, most referrals first.
+    pub fn referrers(&self) -> Vec<ReferrerStats<'_>> {
+        let mut referrers = self
+            .referred
+            .keys()
+            .filter_map(|id| self.stats(id))
+            .collect::<Vec<ReferrerStats>>();
+        referrers.sort_by(|a, b| {
+            b.referred
+                .cmp(&a.referred)
+                .then(b.baptized.cmp(&a.baptized))
+                .then(a.node.name.cmp(&b.node.name))
+        });
+        referrers
+    }
+
+    pub fn stats(&self, id: &str) -> Option<ReferrerStats<'_>> {
+        let node = self.nodes.get(id)?;
+        let referred = self.referred_by(id);
+        Some(ReferrerStats {
+            node,
+            referred: referred.len(),
+            baptized: referred.iter().filter(|n| n.is_baptized()).count(),
+            depth: self.chain_depth(id),
+        })
+    }
+
+    pub fn print_summary(&self) {
+        let (total, baptized) = self.baptism_rate();
+        println!(
+            "{total} people referred by {} referrers, {baptized} baptized ({:.1}%)",
+            self.referred.len(),
+            percent(baptized, total)
+        );
+        let referrers = self.referrers();
+        if let Some(deepest) = referrers.iter().max_by_key(|r| r.depth) {
+            println!(
+                "Longest chain is {} deep, starting with {}",
+                deepest.depth,
+                deepest.node.label()
+            );
+        }
+        for r in referrers.iter().take(10) {
+            println!(
+                "  {}: referred {}, {} baptized ({:.1}%), chain {} deep",
+                r.node.label(),
+                r.referred,
+                r.baptized,
+                percent(r.baptized, r.referred),
+                r.depth
+            );
+        }
+    }
+
+    /// Prints everyone `id` led to, one level of indent per referral.
+    pub fn print_tree(&self, id: &str) {
+        let Some(stats) = self.stats(id) else {
+            return;
+        };
+        println!(
+            "{} referred {}, {} baptized, chain {} deep",
+            stats.node.label(),
+            stats.referred,
+            stats.baptized,
+            stats.depth
+        );
+        self.print_children(id, 1, &mut HashSet::from([id]));
+    }
+
+    fn print_children<'a>(&'a self, id: &str, indent: usize, seen: &mut HashSet<&'a str>) {
+        for node in self.referred_by(id) {
+            println!("{}{}", "  ".repeat(indent), node.label());
+            if seen.insert(&node.id) {
+                self.print_children(&node.id, indent + 1, seen);
+            }
+        }
+    }
+
+    /// The graph for GraphViz, `dot -Tsvg referrals.dot > referrals.svg`.
+    /// Members are filled in.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph referrals {\n    node [shape=box];\n");
+        for node in self.linked() {
+            let style = if node.is_baptized() {
+                ", style=filled"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "    \"{}\" [label=\"{}\"{style}];\n",
+                dot_escape(&node.id),
+                dot_escape(&node.label())
+            ));
+        }
+        for (from, to) in self.edges() {
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\";\n",
+                dot_escape(from),
+                dot_escape(to)
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// The graph as GraphML, for yEd or Gephi.
+    pub fn to_graphml(&self) -> String {
+        let mut out = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n",
+            "  <key id=\"status\" for=\"node\" attr.name=\"status\" attr.type=\"string\"/>\n",
+            "  <key id=\"area\" for=\"node\" attr.name=\"area\" attr.type=\"string\"/>\n",
+            "  <key id=\"baptized\" for=\"node\" attr.name=\"baptized\" attr.type=\"boolean\"/>\n",
+            "  <graph id=\"referrals\" edgedefault=\"directed\">\n",
+        ));
+        for node in self.linked() {
+            out.push_str(&format!("    <node id=\"{}\">\n", xml_escape(&node.id)));
+            out.push_str(&format!(
+                "      <data key=\"name\">{}</data>\n",
+                xml_escape(&node.name)
+            ));
+            if let Some(status) = node.status {
+                out.push_str(&format!(
+                    "      <data key=\"status\">{}</data>\n",
+                    xml_escape(&status.to_string())
+                ));
+            }
+            if let Some(area) = &node.area {
+                out.push_str(&format!(
+                    "      <data key=\"area\">{}</data>\n",
+                    xml_escape(area)
+                ));
+            }
+            out.push_str(&format!(
+                "      <data key=\"baptized\">{}</data>\n    </node>\n",
+                node.is_baptized()
+            ));
+        }
+        for (from, to) in self.edges() {
+            out.push_str(&format!(
+                "    <edge source=\"{}\" target=\"{}\"/>\n",
+                xml_escape(from),
+                xml_escape(to)
+            ));
+        }
+        out.push_str("  </graph>\n</graphml>\n");
+        out
+    }
+
+    /// Everyone who referred or was referred, leaving out everyone else.
+    fn linked(&self) -> impl Iterator<Item = &Node> {
+        self.nodes
+            .values()
+            .filter(|n| n.found_by.is_some() || self.referred.contains_key(&n.id))
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.referred
+            .iter()
+            .flat_map(|(from, to)| to.iter().map(move |t| (from.as_str(), t.as_str())))
+    }
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Escapes a string for inside double quotes, with line breaks as `\n`.
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("\r\n", "\\n")
+        .replace(['\r', '\n'], "\\n")
+}
+
+/// Escapes a string for XML text or attributes, dropping the control
+/// characters XML 1.0 doesn't allow at all.
+fn xml_escape(s: &str) -> String {
+    s.chars()
+        .filter(|c| *c >= ' ' || matches!(c, '\t' | '\n' | '\r'))
+        .filter(|c| !matches!(c, '\u{fffe}' | '\u{ffff}'))
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{commands, contact, with};
+    use serde_json::{json, Value};
+
+    fn referred(id: &str, name: &str, status: u32, found_by: Option<&str>) -> Value {
+        with(
+            contact(id),
+            json!({ "first": name, "status": status, "foundByPersonId": found_by }),
+        )
+    }
+
+    /// x1, who isn't a contact, found Ann (a member) and Ben. Ann found Cal.
+    /// Lee and Max found each other.
+    fn graph() -> ReferralGraph {
+        let area = commands(json!({
+            "contacts": [
+                referred("p1", "Ann", 40, Some("x1")),
+                referred("p2", "Ben", 10, Some("x1")),
+                referred("p3", "Cal", 10, Some("p1")),
+                referred("p4", "Dee", 10, Some("")),
+                referred("l1", "Lee", 10, Some("l2")),
+                referred("l2", "Max", 10, Some("l1")),
+            ],
+        }));
+        ReferralGraph::build(&[area])
+    }
+
+    #[test]
+    fn chain_depth_follows_the_longest_chain() {
+        let graph = graph();
+        assert_eq!(graph.chain_depth("x1"), 2);
+        assert_eq!(graph.chain_depth("p1"), 1);
+        assert_eq!(graph.chain_depth("p3"), 0);
+        assert_eq!(graph.chain_depth("nobody"), 0);
+    }
+
+    #[test]
+    fn chain_depth_stops_at_loops() {
+        let graph = graph();
+        assert_eq!(graph.chain_depth("l1"), 2);
+        assert_eq!(graph.chain_depth("l2"), 2);
+    }
+
+    #[test]
+    fn baptism_rate_counts_referred_members() {
+        // Ann, Ben, Cal, Lee and Max were referred, Dee's empty referrer doesn't count
+        assert_eq!(graph().baptism_rate(), (5, 1));
+    }
+
+    #[test]
+    fn find_by_id_or_name() {
+        let graph = graph();
+        assert_eq!(graph.find("p2").map(|n| n.name.as_str()), Some("Ben"));
+        assert_eq!(graph.find("cal").map(|n| n.id.as_str()), Some("p3"));
+        assert!(graph.find("x1").is_some_and(|n| n.status.is_none()));
+        assert!(graph.find("Zed").is_none());
+    }
+
+    #[test]
+    fn dot_escape_quotes_and_line_breaks() {
+        assert_eq!(dot_escape(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+        assert_eq!(
+            dot_escape("one\ntwo\r\nthree\rfour"),
+            r"one\ntwo\nthree\nfour"
+        );
+    }
+
+    #[test]
+    fn xml_escape_markup_and_control_chars() {
+        assert_eq!(
+            xml_escape(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(xml_escape("a\u{0}b\u{8}c\u{1f}d"), "abcd");
+        assert_eq!(xml_escape("tab\tline\nend\r"), "tab\tline\nend\r");
+        assert_eq!(xml_escape("\u{ffff}é\u{7f}"), "é\u{7f}");
+    }
+}
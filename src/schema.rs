@@ -0,0 +1,323 @@
+// Jackson Coxson
+
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::cache::Resource;
+use crate::error::{parse_json, ApiError};
+use crate::info::{Calling, MissionaryInfo};
+use crate::kic::{KeyIndicator, KeyIndicatorsResponse};
+use crate::timeline::{
+    CommandsResponse, Contact, Drop, Event, PersonEvent, Reset, SacramentAttendance,
+};
+
+/// The record lists in a commands response, checked one record at a time.
+const COMMANDS_LISTS: [&str; 6] = [
+    "personEvents",
+    "events",
+    "personDrops",
+    "personResets",
+    "sacramentAttendance",
+    "contacts",
+];
+
+/// How one response compares to the structs we parse it with. Paths look like
+/// `contacts[].status`, counts are how many records had the problem.
+pub struct SchemaReport {
+    pub source: String,
+    pub records: usize,
+    /// Fields the server sends that we don't model
+    pub unknown: BTreeMap<String, usize>,
+    /// Fields that were null in every record
+    pub always_null: Vec<String>,
+    /// Where a value had the wrong type, and the first error seen there
+    pub mismatched: BTreeMap<String, (usize, String)>,
+    /// Fields we require that the server stopped sending
+    pub missing: BTreeMap<String, usize>,
+}
+
+impl SchemaReport {
+    /// Checks a response of the kind `resource` is against its structs.
+    pub fn check(source: String, resource: &Resource, data: &[u8]) -> Result<Self, ApiError> {
+        let value: Value = parse_json(data)?;
+        let mut report = Self {
+            source,
+            records: 0,
+            unknown: BTreeMap::new(),
+            always_null: Vec::new(),
+            mismatched: BTreeMap::new(),
+            missing: BTreeMap::new(),
+        };
+
+        match resource {
+            Resource::UserDetails => {
+                report.records = 1;
+                report.record::<MissionaryInfo>("", &value);
+                report.nulls("", &[&value]);
+            }
+            Resource::KeyIndicators => {
+                report.top_level::<KeyIndicatorsResponse>(&value, &["areaKeyIndicators"]);
+                report.list::<KeyIndicator>(&value, "areaKeyIndicators");
+            }
+            Resource::Commands(_) => {
+                report.top_level::<CommandsResponse>(&value, &COMMANDS_LISTS);
+                report.list::<PersonEvent>(&value, "personEvents");
+                report.list::<Event>(&value, "events");
+                report.list::<Drop>(&value, "personDrops");
+                report.list::<Reset>(&value, "personResets");
+                report.list::<SacramentAttendance>(&value, "sacramentAttendance");
+                report.list::<Contact>(&value, "contacts");
+            }
+        }
+        Ok(report)
+    }
+
+    /// Whether parsing this response would fail.
+    pub fn breaks(&self) -> bool {
+        !self.mismatched.is_empty() || !self.missing.is_empty()
+    }
+
+    /// Checks the fields outside the record lists, with the lists emptied.
+    fn top_level<T: DeserializeOwned + Unmodeled>(&mut self, value: &Value, lists: &[&str]) {
+        let mut value = value.clone();
+        if let Value::Object(map) = &mut value {
+            for list in lists {
+                if let Some(v) = map.get_mut(*list) {
+                    *v = Value::Array(Vec::new());
+                }
+            }
+        }
+        self.record::<T>("", &value);
+    }
+
+    /// Checks every record in a list on its own, so one bad record doesn't
+    /// hide the rest.
+    fn list<T: DeserializeOwned + Unmodeled>(&mut self, value: &Value, key: &str) {
+        let Some(Value::Array(items)) = value.get(key) else {
+            return;
+        };
+        let prefix = format!("{key}[]");
+        for item in items {
+            self.record::<T>(&prefix, item);
+        }
+        self.records += items.len();
+        self.nulls(&prefix, &items.iter().collect::<Vec<&Value>>());
+    }
+
+    fn record<T: DeserializeOwned + Unmodeled>(&mut self, prefix: &str, value: &Value) {
+        let mut unknown = Vec::new();
+        let mut note = |path: serde_ignored::Path| unknown.push(path.to_string());
+        let de = serde_ignored::Deserializer::new(value, &mut note);
+        match serde_path_to_error::deserialize::<_, T>(de) {
+            Ok(parsed) => unknown.extend(parsed.unmodeled()),
+            Err(e) => {
+                let path = join(prefix, &e.path().to_string());
+                let message = e.inner().to_string();
+                match message
+                    .strip_prefix("missing field `")
+                    .and_then(|m| m.split('`').next())
+                {
+                    Some(field) => *self.missing.entry(join(&path, field)).or_default() += 1,
+                    None => self.mismatched.entry(path).or_insert((0, message)).0 += 1,
+                }
+            }
+        }
+        for path in unknown {
+            *self.unknown.entry(join(prefix, &path)).or_default() += 1;
+        }
+    }
+
+    /// Notes the fields that are null in every one of `records`.
+    fn nulls(&mut self, prefix: &str, records: &[&Value]) {
+        let mut seen: BTreeMap<&str, bool> = BTreeMap::new();
+        for record in records {
+            if let Value::Object(map) = record {
+                for (key, v) in map {
+                    let all_null = seen.entry(key).or_insert(true);
+                    *all_null &= v.is_null();
+                }
+            }
+        }
+        self.always_null.extend(
+            seen.into_iter()
+                .filter(|(_, all_null)| *all_null)
+                .map(|(key, _)| join(prefix, key)),
+        );
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{}: {} records, {} unknown, {} always null, {} mismatched, {} missing",
+            self.source,
+            self.records,
+            self.unknown.len(),
+            self.always_null.len(),
+            self.mismatched.len(),
+            self.missing.len()
+        );
+        for (path, count) in &self.unknown {
+            println!("  unknown field {path} in {count} records");
+        }
+        for path in &self.always_null {
+            println!("  always null {path}");
+        }
+        for (path, (count, message)) in &self.mismatched {
+            println!("  type mismatch at {path} in {count} records: {message}");
+        }
+        for (path, count) in &self.missing {
+            println!("  newly required {path} missing from {count} records");
+        }
+    }
+}
+
+/// Models that keep the fields they don't know about in `extra`, which
+/// serde_ignored can't see past. Every model has one, flattened, so whatever
+/// the server sent that we don't model is kept instead of lost. Serializing
+/// gives back the same content, nulls included.
+trait Unmodeled {
+    /// Paths of every field that ended up in an `extra` map.
+    fn unmodeled(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+fn keys(extra: &Map<String, Value>) -> Vec<String> {
+    extra.keys().cloned().collect()
+}
+
+impl Unmodeled for MissionaryInfo {
+    fn unmodeled(&self) -> Vec<String> {
+        let mut paths = keys(&self.extra);
+        for calling in self.callings.iter().flatten().flatten() {
+            paths.extend(
+                calling
+                    .unmodeled()
+                    .into_iter()
+                    .map(|p| format!("callings.0.{p}")),
+            );
+        }
+        paths
+    }
+}
+
+macro_rules! unmodeled_from_extra {
+    ($($model:ty),*) => {
+        $(impl Unmodeled for $model {
+            fn unmodeled(&self) -> Vec<String> {
+                keys(&self.extra)
+            }
+        })*
+    };
+}
+
+unmodeled_from_extra!(
+    Calling,
+    CommandsResponse,
+    PersonEvent,
+    Event,
+    Drop,
+    Reset,
+    SacramentAttendance,
+    Contact
+);
+
+// Not preserved yet, serde_ignored reports these
+impl Unmodeled for KeyIndicatorsResponse {}
+impl Unmodeled for KeyIndicator {}
+
+/// Joins paths, dropping array indices so every record's problems add up in
+/// the same place: `contacts[]` and `3.note` make `contacts[].note`.
+fn join(prefix: &str, path: &str) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    // serde_ignored writes `?` for the inside of an Option
+    for part in path.split('.').filter(|p| !p.is_empty() && *p != "?") {
+        if part.chars().all(|c| c.is_ascii_digit()) {
+            match parts.last_mut() {
+                Some(last) => last.push_str("[]"),
+                None => continue,
+            }
+        } else {
+            match part.find('[') {
+                Some(i) => parts.push(format!("{}[]", &part[..i])),
+                None => parts.push(part.to_string()),
+            }
+        }
+    }
+    let path = parts.join(".");
+    match (prefix.is_empty(), path.is_empty()) {
+        (_, true) => prefix.to_string(),
+        (true, false) => path,
+        (false, false) => format!("{prefix}.{path}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{contact, event, with};
+    use serde_json::json;
+
+    fn check(commands: Value) -> SchemaReport {
+        let data = serde_json::to_vec(&commands).unwrap();
+        SchemaReport::check("test".to_string(), &Resource::Commands("1".into()), &data).unwrap()
+    }
+
+    #[test]
+    fn fixture_matches_the_structs() {
+        let data = include_bytes!("../fixtures/commands/30010.json");
+        let report = SchemaReport::check(
+            "30010".to_string(),
+            &Resource::Commands("30010".into()),
+            data,
+        )
+        .unwrap();
+        assert!(report.records > 0);
+        assert!(report.unknown.is_empty());
+        assert!(!report.breaks());
+    }
+
+    #[test]
+    fn reports_unknown_missing_mismatched_and_null_fields() {
+        let mut missing = contact("p3");
+        missing.as_object_mut().unwrap().remove("createDate");
+        let report = check(json!({
+            "personEvents": [],
+            "events": [
+                with(event("e1", 0), json!({ "subject": null })),
+                with(event("e2", 0), json!({ "subject": null })),
+            ],
+            "personDrops": [],
+            "personResets": [],
+            "sacramentAttendance": [],
+            "contacts": [
+                with(contact("p1"), json!({ "favoriteColor": "blue" })),
+                with(contact("p2"), json!({ "favoriteColor": "red", "status": "ten" })),
+                missing,
+                with(contact("p4"), json!({ "status": "ten" })),
+            ],
+            "prosAreaName": "Test Area",
+            "syncToken": "abc",
+        }));
+
+        assert_eq!(report.records, 6);
+        // p2 doesn't parse, so its unknown field isn't counted
+        assert_eq!(
+            report.unknown,
+            BTreeMap::from([
+                ("contacts[].favoriteColor".to_string(), 1),
+                ("syncToken".to_string(), 1),
+            ])
+        );
+        assert_eq!(report.always_null, ["events[].subject"]);
+        let (count, _) = &report.mismatched["contacts[].status"];
+        assert_eq!(*count, 2);
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(
+            report.missing,
+            BTreeMap::from([("contacts[].createDate".to_string(), 1)])
+        );
+        assert!(report.breaks());
+    }
+}
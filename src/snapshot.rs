@@ -0,0 +1,296 @@
+// Jackson Coxson
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::crypto::{self, CacheKey};
+use crate::error::{parse_json, ApiError};
+use crate::timeline::CommandsResponse;
+
+/// How snapshot file names encode the time they were taken.
+const SNAPSHOT_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// Which snapshots survive a prune.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Every snapshot younger than this is kept
+    pub keep_all_days: i64,
+    /// Past `keep_all_days`, the last snapshot of each week is kept until this age
+    pub keep_weekly_days: i64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_all_days: 30,
+            keep_weekly_days: 365,
+        }
+    }
+}
+
+/// Every sync of an area, kept as `history/{area}/{time}.json.gz`. Lives
+/// outside the cache so purging the cache doesn't lose it.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    root: PathBuf,
+    retention: RetentionPolicy,
+    /// Encrypts snapshots after they're compressed
+    key: Option<Arc<CacheKey>>,
+}
+
+impl SnapshotStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            retention: RetentionPolicy::default(),
+            key: None,
+        }
+    }
+
+    pub fn with_key(mut self, key: Arc<CacheKey>) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn area_dir(&self, area: &str) -> PathBuf {
+        self.root.join(area)
+    }
+
+    fn snapshot_path(&self, area: &str, taken: DateTime<Utc>) -> PathBuf {
+        self.area_dir(area)
+            .join(format!("{}.json.gz", taken.format(SNAPSHOT_FORMAT)))
+    }
+
+    /// Stores the state of an area as it was at `taken`, then prunes old snapshots.
+    pub async fn save(
+        &self,
+        area: &str,
+        taken: DateTime<Utc>,
+        res: &CommandsResponse,
+    ) -> Result<(), ApiError> {
+        let json = serde_json::to_vec(res).expect("CommandsResponse always serializes");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&json)?;
+        let data = crypto::seal(self.key.as_deref(), &encoder.finish()?);
+
+        fs::create_dir_all(self.area_dir(area)).await?;
+        // Snapshots are never overwritten, two in the same millisecond move
+        // the second one along
+        let mut taken = taken;
+        let mut file = loop {
+            let opened = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.snapshot_path(area, taken))
+                .await;
+            match opened {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    taken += Duration::milliseconds(1);
+                }
+                opened => break opened?,
+            }
+        };
+        file.write_all(&data).await?;
+
+        self.prune(area, Utc::now()).await
+    }
+
+    /// When each snapshot of an area was taken, oldest first.
+    pub async fn list(&self, area: &str) -> Result<Vec<DateTime<Utc>>, ApiError> {
+        let mut dir = match fs::read_dir(self.area_dir(area)).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut taken = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(stamp) = name.strip_suffix(".json.gz") {
+                if let Ok(t) = NaiveDateTime::parse_from_str(stamp, SNAPSHOT_FORMAT) {
+                    taken.push(t.and_utc());
+                }
+            }
+        }
+        taken.sort();
+        Ok(taken)
+    }
+
+    /// Every area with at least one snapshot.
+    pub async fn areas(&self) -> Result<Vec<String>, ApiError> {
+        let mut dir = match fs::read_dir(&self.root).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut areas = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                areas.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        areas.sort();
+        Ok(areas)
+    }
+
+    /// Loads the area as it was at `at`, meaning the newest snapshot taken at
+    /// or before then. `None` if there's no snapshot that old.
+    pub async fn load_as_of(
+        &self,
+        area: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<(DateTime<Utc>, CommandsResponse)>, ApiError> {
+        let taken = match self.list(area).await?.into_iter().rev().find(|t| *t <= at) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let data = crypto::open(
+            self.key.as_deref(),
+            fs::read(self.snapshot_path(area, taken)).await?,
+        )?;
+        let mut json = Vec::new();
+        GzDecoder::new(&data[..])
+            .read_to_end(&mut json)
+            .map_err(|source| ApiError::Decompress {
+                encoding: "gzip".to_string(),
+                source,
+            })?;
+        Ok(Some((taken, parse_json(&json)?)))
+    }
+
+    /// Encrypts every plaintext snapshot in place, shredding the originals.
+    /// Returns how many were encrypted.
+    pub async fn encrypt_all(&self) -> Result<usize, ApiError> {
+        let key = self
+            .key
+            .as_deref()
+            .ok_or(ApiError::Encryption("no passphrase"))?;
+        let mut count = 0;
+        crypto::unlock(&self.root, key).await?;
+        for area in self.areas().await? {
+            for taken in self.list(&area).await? {
+                if crypto::encrypt_file(key, &self.snapshot_path(&area, taken)).await? {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Deletes the snapshots the retention policy doesn't keep.
+    pub async fn prune(&self, area: &str, now: DateTime<Utc>) -> Result<(), ApiError> {
+        let keep_all = now - Duration::days(self.retention.keep_all_days);
+        let keep_weekly = now - Duration::days(self.retention.keep_weekly_days);
+
+        let taken = self.list(area).await?;
+        for (i, t) in taken.iter().enumerate() {
+            let keep = if *t >= keep_all {
+                true
+            } else if *t >= keep_weekly {
+                // Keep it if it's the last one of its week
+                match taken.get(i + 1) {
+                    Some(next) => next.iso_week() != t.iso_week(),
+                    None => true,
+                }
+            } else {
+                false
+            };
+
+            if !keep {
+                fs::remove_file(self.snapshot_path(area, *t)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, 12, 0, 0).unwrap()
+    }
+
+    fn commands() -> CommandsResponse {
+        serde_json::from_str(include_str!("../fixtures/commands/30010.json")).unwrap()
+    }
+
+    /// A store that keeps everything it's given, so tests pick when to prune.
+    fn store(root: &Path) -> SnapshotStore {
+        SnapshotStore::new(root).with_retention(RetentionPolicy {
+            keep_all_days: 100_000,
+            keep_weekly_days: 100_000,
+        })
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_recent_and_weekly_snapshots() {
+        let dir = std::env::temp_dir().join(format!("presi-test-{}", uuid::Uuid::new_v4()));
+        let res = commands();
+        // 2024-04-01 and 2024-04-03 are the same week, 2024-04-10 is the next
+        for taken in [
+            at(2023, 1, 1),
+            at(2024, 4, 1),
+            at(2024, 4, 3),
+            at(2024, 4, 10),
+            at(2024, 5, 20),
+        ] {
+            store(&dir).save("1", taken, &res).await.unwrap();
+        }
+        let pruned = SnapshotStore::new(&dir).with_retention(RetentionPolicy {
+            keep_all_days: 30,
+            keep_weekly_days: 365,
+        });
+        pruned.prune("1", at(2024, 6, 1)).await.unwrap();
+        let left = pruned.list("1").await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(left, [at(2024, 4, 3), at(2024, 4, 10), at(2024, 5, 20)]);
+    }
+
+    #[tokio::test]
+    async fn load_as_of_finds_the_newest_snapshot_before() {
+        let dir = std::env::temp_dir().join(format!("presi-test-{}", uuid::Uuid::new_v4()));
+        let store = store(&dir);
+        let res = commands();
+        store.save("1", at(2024, 4, 1), &res).await.unwrap();
+        store.save("1", at(2024, 4, 10), &res).await.unwrap();
+        // Same time again moves along a millisecond instead of overwriting
+        store.save("1", at(2024, 4, 10), &res).await.unwrap();
+
+        let taken = |found: Option<(DateTime<Utc>, CommandsResponse)>| found.map(|(t, _)| t);
+        let before = taken(store.load_as_of("1", at(2024, 3, 1)).await.unwrap());
+        let between = taken(store.load_as_of("1", at(2024, 4, 5)).await.unwrap());
+        let after = store.load_as_of("1", at(2024, 5, 1)).await.unwrap();
+        let other = taken(store.load_as_of("2", at(2024, 5, 1)).await.unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(before, None);
+        assert_eq!(between, Some(at(2024, 4, 1)));
+        let (after_taken, after) = after.unwrap();
+        assert_eq!(after_taken, at(2024, 4, 10) + Duration::milliseconds(1));
+        assert_eq!(after.contacts.len(), res.contacts.len());
+        assert_eq!(other, None);
+    }
+}
@@ -1,10 +1,13 @@
 // Jackson Coxson
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
 use csv::Writer;
 
-use crate::timeline::{Person, TimelineEventKind};
+use crate::codes::ContactStatus;
+use crate::household::Household;
+use crate::timeline::{Person, TimelineEntry, TimelineEventKind};
 
 const MIN_TIME: u64 = 12 * 60 * 60 * 1000;
 const MILLIS_IN_DAY: f64 = 1000.0 * 60.0 * 60.0 * 24.0;
@@ -12,78 +15,230 @@ const MILLIS_IN_DAY: f64 = 1000.0 * 60.0 * 60.0 * 24.0;
 // Stats to print:
 // Average time between contacts
 
-pub fn print_stats(people: Vec<Person>, start: u64, end: u64) {
+/// How an event with several people in it counts toward the stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Counting {
+    /// Once for every person in it, so a family lesson with three people is
+    /// three contacts
+    #[default]
+    PerPerson,
+    /// Once, split evenly between everyone in it
+    PerEvent,
+}
+
+impl FromStr for Counting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "person" => Ok(Counting::PerPerson),
+            "event" => Ok(Counting::PerEvent),
+            other => Err(format!("{other} isn't person or event")),
+        }
+    }
+}
+
+/// Whether the stats follow each person or each household.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Grouping {
+    #[default]
+    Person,
+    /// Everyone sharing a `householdId` together, so a family taught together
+    /// is one set of contacts instead of one per person
+    Household,
+}
+
+impl FromStr for Grouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "person" => Ok(Grouping::Person),
+            "household" => Ok(Grouping::Household),
+            other => Err(format!("{other} isn't person or household")),
+        }
+    }
+}
+
+/// One person or household, whichever the stats are following.
+struct Unit<'a> {
+    area: &'a str,
+    ids: Vec<&'a str>,
+    timeline: Vec<&'a TimelineEntry>,
+}
+
+impl<'a> Unit<'a> {
+    fn all(people: &'a [Person], grouping: Grouping) -> Vec<Unit<'a>> {
+        match grouping {
+            Grouping::Person => people
+                .iter()
+                .map(|p| Unit {
+                    area: &p.area,
+                    ids: vec![p.id.as_str()],
+                    timeline: p.timeline.iter().collect(),
+                })
+                .collect(),
+            Grouping::Household => Household::group(people)
+                .iter()
+                .map(|h| Unit {
+                    area: h.area,
+                    ids: h.member_ids(),
+                    timeline: h.timeline(),
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn print_stats(
+    people: Vec<Person>,
+    start: u64,
+    end: u64,
+    counting: Counting,
+    grouping: Grouping,
+) {
+    export_people(&people, "people.csv").unwrap();
+    if grouping == Grouping::Household {
+        print_household_statuses(&people);
+    }
+    let units = Unit::all(&people, grouping);
+    let (contacts, events, group_events) = count_contacts(&units, start, end);
+    let with = match grouping {
+        Grouping::Person => "people",
+        Grouping::Household => "households",
+    };
+    println!(
+        "{contacts} contacts with {with} in {events} events, {group_events} of them group events"
+    );
     println!(
         "{} days between contacts",
-        average_contact_time(&people, start, end) / MILLIS_IN_DAY
+        average_contact_time(&units, start, end, counting) / MILLIS_IN_DAY
     );
 }
 
-fn average_contact_time(people: &Vec<Person>, start: u64, end: u64) -> f64 {
-    let mut times = Vec::new();
-    let mut areas: HashMap<&String, Vec<u64>> = HashMap::new();
+/// How many households there are of each status.
+fn print_household_statuses(people: &[Person]) {
+    let households = Household::group(people);
+    let mut statuses: HashMap<ContactStatus, usize> = HashMap::new();
+    for household in &households {
+        *statuses.entry(household.status()).or_default() += 1;
+    }
+    println!("{} households", households.len());
+    let mut statuses = statuses.into_iter().collect::<Vec<_>>();
+    statuses.sort_by_key(|(status, _)| u32::from(*status));
+    for (status, count) in statuses {
+        println!("  {count} {status}");
+    }
+}
+
+/// Contacts counted per person or household, distinct events, and how many
+/// of those were with more than one person, between `start` and `end`.
+fn count_contacts(units: &[Unit], start: u64, end: u64) -> (usize, usize, usize) {
+    let mut contacts = 0;
+    let mut events = HashSet::new();
+    let mut group_events = HashSet::new();
+    for e in units.iter().flat_map(|u| u.timeline.iter()) {
+        let Some(event) = e.event() else {
+            continue;
+        };
+        if e.time < start || e.time > end {
+            continue;
+        }
+        contacts += 1;
+        events.insert(&event.id);
+        if e.is_group() {
+            group_events.insert(&event.id);
+        }
+    }
+    (contacts, events.len(), group_events.len())
+}
+
+fn average_contact_time(units: &[Unit], start: u64, end: u64, counting: Counting) -> f64 {
+    let gaps = contact_gaps(units, start, end, counting);
+    let times = gaps
+        .iter()
+        .map(|(_, gap, weight)| (*gap, *weight))
+        .collect::<Vec<(u64, f64)>>();
+    let mut areas: HashMap<&str, Vec<(u64, f64)>> = HashMap::new();
+    for (area, gap, weight) in gaps {
+        areas.entry(area).or_default().push((gap, weight));
+    }
 
-    for person in people {
-        // println!("----- {} ------", person.name);
-        let mut dropped = false;
+    let areas = areas
+        .into_iter()
+        .map(|(k, v)| (k, weighted_average(&v) / MILLIS_IN_DAY))
+        .collect::<HashMap<&str, f64>>();
+
+    println!("{areas:#?}");
+    export_to_csv(&areas, "export.csv").unwrap();
+
+    weighted_average(&times)
+}
+
+/// Every gap between contacts between `start` and `end`, with the area it was
+/// in and how much it counts.
+fn contact_gaps<'a>(
+    units: &[Unit<'a>],
+    start: u64,
+    end: u64,
+    counting: Counting,
+) -> Vec<(&'a str, u64, f64)> {
+    let mut gaps = Vec::new();
+
+    for unit in units {
+        // A household is only dropped once everyone in it is
+        let mut dropped = HashSet::new();
         let mut pivot = None;
 
-        for e in person.timeline.iter() {
-            match e.kind {
-                TimelineEventKind::Event => {
-                    if dropped {
+        for e in unit.timeline.iter() {
+            match &e.kind {
+                TimelineEventKind::Event { .. } => {
+                    if dropped.len() >= unit.ids.len() {
                         continue;
                     }
                     if e.time < start || e.time > end {
                         continue;
                     }
-                    // println!("-- {}", e.details);
                     if let Some(pivot) = pivot {
                         let time_difference = e.time - pivot;
                         if time_difference > MIN_TIME {
-                            times.push(time_difference);
-                            if let Some(l) = areas.get_mut(&person.area) {
-                                l.push(time_difference);
-                            } else {
-                                areas.insert(&person.area, vec![time_difference]);
-                            }
+                            let weight = match counting {
+                                Counting::PerPerson => 1.0,
+                                Counting::PerEvent => e.share(&unit.ids),
+                            };
+                            gaps.push((unit.area, time_difference, weight));
                         }
                     }
                     pivot = Some(e.time);
                 }
-                TimelineEventKind::Drop => {
-                    // println!("--- DROP");
-                    dropped = true;
+                TimelineEventKind::Drop(d) => {
+                    dropped.insert(&d.person_id);
                 }
-                TimelineEventKind::Reset => {
-                    // println!("--- RESET");
-                    dropped = false;
-                    pivot = None;
+                TimelineEventKind::Reset(r) => {
+                    // A household only starts over when it comes back from
+                    // being dropped, not when one member is reset while the
+                    // rest are still being taught
+                    if unit.ids.len() == 1 || dropped.len() >= unit.ids.len() {
+                        pivot = None;
+                    }
+                    dropped.remove(&r.person_id);
                 }
-                TimelineEventKind::Sacrament => {}
+                TimelineEventKind::Sacrament(_) => {}
             }
         }
     }
 
-    let areas = areas
-        .into_iter()
-        .map(|(k, v)| {
-            (
-                k,
-                (v.iter().sum::<u64>() as f64 / v.len() as f64) / MILLIS_IN_DAY,
-            )
-        })
-        .collect::<HashMap<&String, f64>>();
-
-    println!("{areas:#?}");
-    export_to_csv(&areas, "export.csv").unwrap();
+    gaps
+}
 
-    let sum = times.iter().sum::<u64>() as f64;
-    sum / times.len() as f64
+fn weighted_average(values: &[(u64, f64)]) -> f64 {
+    let sum = values.iter().map(|(v, w)| *v as f64 * w).sum::<f64>();
+    let weights = values.iter().map(|(_, w)| w).sum::<f64>();
+    sum / weights
 }
 
 fn export_to_csv(
-    data: &HashMap<&String, f64>,
+    data: &HashMap<&str, f64>,
     file_path: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut writer = Writer::from_path(file_path)?;
@@ -93,9 +248,157 @@ fn export_to_csv(
 
     // Write each key-value pair
     for (key, value) in data {
-        writer.write_record([key, &value.to_string()])?;
+        writer.write_record([key.to_string(), value.to_string()])?;
     }
 
     writer.flush()?;
     Ok(())
 }
+
+/// Columns of `people.csv`.
+const PEOPLE_COLUMNS: [&str; 12] = [
+    "id",
+    "householdId",
+    "name",
+    "first",
+    "last",
+    "gender",
+    "ageCategory",
+    "status",
+    "created",
+    "language",
+    "area",
+    "areaId",
+];
+
+/// Writes one row per person, keyed by the contact id so it can be joined
+/// with other exports and snapshots.
+fn export_people(people: &[Person], file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = Writer::from_path(file_path)?;
+    writer.write_record(PEOPLE_COLUMNS)?;
+    for p in people {
+        writer.write_record(person_record(p))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn person_record(p: &Person) -> [String; PEOPLE_COLUMNS.len()] {
+    let text = |s: &Option<String>| s.clone().unwrap_or_default();
+    [
+        p.id.clone(),
+        p.household_id.clone(),
+        p.display_name(),
+        text(&p.first_name),
+        text(&p.last_name),
+        text(&p.gender),
+        p.age_category.map(|a| a.to_string()).unwrap_or_default(),
+        p.status.to_string(),
+        p.created.to_string(),
+        p.language.map(|l| l.to_string()).unwrap_or_default(),
+        p.area.clone(),
+        p.area_id.map(|a| a.to_string()).unwrap_or_default(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timeline::test_data::{commands, contact, event, with};
+    use chrono_tz::Tz;
+    use serde_json::{json, Value};
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    /// Two people in one household, with lessons on the given days.
+    fn household(
+        lessons: &[(u64, &[&str])],
+        drops: &[(&str, u64)],
+        resets: &[(&str, u64)],
+    ) -> Vec<Person> {
+        let events = lessons
+            .iter()
+            .enumerate()
+            .map(|(i, (day, _))| event(&format!("e{i}"), day * DAY))
+            .collect::<Vec<Value>>();
+        let person_events = lessons
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, ids))| {
+                ids.iter().map(move |id| {
+                    json!({ "id": format!("pe{i}{id}"), "personId": id, "eventId": format!("e{i}") })
+                })
+            })
+            .collect::<Vec<Value>>();
+        let drops = drops
+            .iter()
+            .enumerate()
+            .map(|(i, (id, day))| {
+                json!({ "id": format!("d{i}"), "personId": id, "dropDate": day * DAY, "status": 1 })
+            })
+            .collect::<Vec<Value>>();
+        let resets = resets
+            .iter()
+            .enumerate()
+            .map(|(i, (id, day))| {
+                json!({ "id": format!("r{i}"), "personId": id, "resetDate": day * DAY })
+            })
+            .collect::<Vec<Value>>();
+        let in_h1 = |id| with(contact(id), json!({ "householdId": "h1" }));
+        let res = commands(json!({
+            "personEvents": person_events,
+            "events": events,
+            "personDrops": drops,
+            "personResets": resets,
+            "contacts": [in_h1("p1"), in_h1("p2")],
+        }));
+        let mut people = res.into_people(Tz::UTC);
+        people.sort_by(|a, b| a.id.cmp(&b.id));
+        people
+    }
+
+    fn gaps(people: &[Person], grouping: Grouping) -> Vec<u64> {
+        let units = Unit::all(people, grouping);
+        contact_gaps(&units, 0, u64::MAX, Counting::PerPerson)
+            .into_iter()
+            .map(|(_, gap, _)| gap / DAY)
+            .collect()
+    }
+
+    #[test]
+    fn one_member_reset_keeps_the_household_going() {
+        let people = household(&[(0, &["p1"]), (4, &["p1"])], &[("p2", 1)], &[("p2", 2)]);
+        assert_eq!(gaps(&people, Grouping::Household), vec![4]);
+    }
+
+    #[test]
+    fn household_starts_over_when_reset_after_everyone_dropped() {
+        let people = household(
+            &[(0, &["p1"]), (2, &["p1"]), (5, &["p2"]), (7, &["p2"])],
+            &[("p1", 1), ("p2", 1)],
+            &[("p2", 3)],
+        );
+        assert_eq!(gaps(&people, Grouping::Household), vec![2]);
+    }
+
+    #[test]
+    fn person_reset_always_starts_over() {
+        let people = household(
+            &[(0, &["p1"]), (3, &["p1"]), (5, &["p1"])],
+            &[],
+            &[("p1", 1)],
+        );
+        assert_eq!(gaps(&people, Grouping::Person), vec![2]);
+    }
+
+    #[test]
+    fn people_export_has_names_and_ids() {
+        let people = household(&[], &[], &[]);
+        let row = person_record(&people[0]);
+        assert_eq!(row[0], "p1");
+        assert_eq!(row[1], "h1");
+        assert_eq!(row[2], "p1");
+        assert_eq!(row[10], "Test Area");
+        assert_eq!(row[11], "");
+    }
+}
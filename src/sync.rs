@@ -0,0 +1,148 @@
+// Jackson Coxson
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs;
+
+use crate::cache::Cache;
+use crate::error::{parse_json, ApiError};
+
+/// Sent as `syncStrategy` when we want everything. This is the only value
+/// we've seen the app send on a fresh install.
+const FULL_SYNC: &str = "F";
+/// Sent as `syncStrategy` when we only want what changed since `lastSync`.
+/// Unverified: a guess, the app hasn't been seen sending it.
+const DELTA_SYNC: &str = "D";
+/// A delta only adds and updates records, so anything the server deleted
+/// stays until a full sync replaces the whole area. One is done this often.
+pub const FULL_SYNC_EVERY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// What the server needs to know to send only what changed in an area.
+/// Stored in `cache/sync/{area}.json`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncState {
+    /// Generated once per area, the app does the same per install
+    pub sync_guid: String,
+    /// Unix timestamp in milliseconds of the last successful sync. Sent as
+    /// `lastSync`; unverified, the app's format hasn't been seen.
+    pub last_sync: Option<u64>,
+    /// Unix timestamp in milliseconds of the last full sync
+    pub last_full_sync: Option<u64>,
+}
+
+impl SyncState {
+    fn path(cache: &Cache, area: &str) -> PathBuf {
+        cache.root().join("sync").join(format!("{area}.json"))
+    }
+
+    /// Loads the sync state for an area, or starts a new one.
+    pub async fn load(cache: &Cache, area: &str) -> Result<Self, ApiError> {
+        match fs::read_to_string(Self::path(cache, area)).await {
+            Ok(data) => parse_json(&data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self {
+                sync_guid: uuid::Uuid::new_v4().to_string(),
+                last_sync: None,
+                last_full_sync: None,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, cache: &Cache, area: &str) -> Result<(), ApiError> {
+        fs::create_dir_all(cache.root().join("sync")).await?;
+        let data = serde_json::to_string_pretty(self).expect("SyncState always serializes");
+        fs::write(Self::path(cache, area), data).await?;
+        Ok(())
+    }
+
+    /// Forgets the sync state so the next sync of the area is a full one.
+    pub async fn remove(cache: &Cache, area: &str) -> Result<(), ApiError> {
+        match fs::remove_file(Self::path(cache, area)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the next sync, at `now` in milliseconds, should start over
+    /// instead of asking for a delta.
+    pub fn full_sync_due(&self, now: u64) -> bool {
+        match (self.last_sync, self.last_full_sync) {
+            (Some(_), Some(full)) => now.saturating_sub(full) >= FULL_SYNC_EVERY.as_millis() as u64,
+            _ => true,
+        }
+    }
+
+    /// The `syncStrategy` header for the next request.
+    pub fn strategy(&self) -> &'static str {
+        match self.last_sync {
+            Some(_) => DELTA_SYNC,
+            None => FULL_SYNC,
+        }
+    }
+
+    /// The `lastSync` header for the next request, empty for a full sync.
+    pub fn last_sync_header(&self) -> String {
+        self.last_sync.map(|t| t.to_string()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEEK: u64 = 7 * 24 * 60 * 60 * 1000;
+
+    fn state(last_sync: Option<u64>, last_full_sync: Option<u64>) -> SyncState {
+        SyncState {
+            sync_guid: "guid".to_string(),
+            last_sync,
+            last_full_sync,
+        }
+    }
+
+    #[test]
+    fn headers_follow_the_last_sync() {
+        let new = state(None, None);
+        assert_eq!(new.strategy(), FULL_SYNC);
+        assert_eq!(new.last_sync_header(), "");
+        let synced = state(Some(1_700_000_000_000), Some(1_700_000_000_000));
+        assert_eq!(synced.strategy(), DELTA_SYNC);
+        assert_eq!(synced.last_sync_header(), "1700000000000");
+    }
+
+    #[test]
+    fn full_sync_every_week() {
+        assert!(state(None, None).full_sync_due(0));
+        assert!(state(Some(WEEK), None).full_sync_due(WEEK));
+        assert!(!state(Some(WEEK), Some(WEEK)).full_sync_due(2 * WEEK - 1));
+        assert!(state(Some(2 * WEEK), Some(WEEK)).full_sync_due(2 * WEEK));
+        // A clock that went backwards isn't a reason to start over
+        assert!(!state(Some(WEEK), Some(WEEK)).full_sync_due(0));
+    }
+
+    #[tokio::test]
+    async fn saved_state_loads_back() {
+        let cache =
+            Cache::new(std::env::temp_dir().join(format!("presi-test-{}", uuid::Uuid::new_v4())));
+        let fresh = SyncState::load(&cache, "30010").await.unwrap();
+        state(Some(5), Some(3)).save(&cache, "30010").await.unwrap();
+        let loaded = SyncState::load(&cache, "30010").await;
+        SyncState::remove(&cache, "30010").await.unwrap();
+        let removed = SyncState::load(&cache, "30010").await;
+        let _ = std::fs::remove_dir_all(cache.root());
+
+        assert_eq!(fresh.last_sync, None);
+        assert_eq!(fresh.sync_guid.len(), 36);
+        let loaded = loaded.unwrap();
+        assert_eq!(
+            (
+                loaded.sync_guid.as_str(),
+                loaded.last_sync,
+                loaded.last_full_sync
+            ),
+            ("guid", Some(5), Some(3))
+        );
+        assert_eq!(removed.unwrap().last_sync, None);
+    }
+}
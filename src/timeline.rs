@@ -1,33 +1,85 @@
 // timeline.rs
 
+use std::collections::{HashMap, HashSet};
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::codes::{
+    AgeCategory, ContactSource, ContactStatus, ContactType, DropStatus, EventType, OwnerStatus,
+    VisibilityType,
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Event {
     pub id: String, // UUID as a String
-    #[serde(rename = "modBy")]
-    pub modified_by: Option<String>,
-    #[serde(rename = "startTime")]
-    pub start_time: Option<u64>, // Unix timestamp in milliseconds
-    #[serde(rename = "endTime")]
-    pub end_time: Option<u64>, // Unix timestamp in milliseconds
+    #[serde(
+        rename = "modBy",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub modified_by: Option<Option<String>>,
+    #[serde(
+        rename = "startTime",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub start_time: Option<Option<u64>>, // Unix timestamp in milliseconds
+    #[serde(
+        rename = "endTime",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub end_time: Option<Option<u64>>, // Unix timestamp in milliseconds
     #[serde(rename = "eventType")]
-    pub event_type: u32,
-    pub subject: Option<String>,
-    #[serde(rename = "lessonPlan")]
-    pub report: Option<String>,
-    #[serde(rename = "contactTypeId")]
-    pub contact_type_id: Option<u32>,
-    #[serde(rename = "creationDate")]
-    pub creation_date: Option<u64>, // Unix timestamp in milliseconds
-    #[serde(rename = "updatedDate")]
-    pub updated_date: Option<u64>, // Unix timestamp in milliseconds
+    pub event_type: EventType,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub subject: Option<Option<String>>,
+    #[serde(
+        rename = "lessonPlan",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub report: Option<Option<String>>,
+    #[serde(
+        rename = "contactTypeId",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub contact_type_id: Option<Option<ContactType>>,
+    #[serde(
+        rename = "creationDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub creation_date: Option<Option<u64>>, // Unix timestamp in milliseconds
+    #[serde(
+        rename = "updatedDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub updated_date: Option<Option<u64>>, // Unix timestamp in milliseconds
     #[serde(rename = "lessonYN")]
     pub lesson_yn: u8, // Boolean as 0/1
     #[serde(rename = "ownerYN")]
     pub owner_yn: u8, // Boolean as 0/1
     #[serde(rename = "memberPresentYN")]
     pub member_present_yn: u8, // Boolean as 0/1
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -37,6 +89,8 @@ pub struct PersonEvent {
     pub person_id: String,
     #[serde(rename = "eventId")]
     pub event_id: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -46,9 +100,21 @@ pub struct Drop {
     pub person_id: String,
     #[serde(rename = "dropDate")]
     pub drop_date: u64,
-    pub note: Option<String>,
-    pub created_by: Option<String>,
-    pub status: u8,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub note: Option<Option<String>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub created_by: Option<Option<String>>,
+    pub status: DropStatus,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -58,7 +124,14 @@ pub struct Reset {
     pub person_id: String,
     #[serde(rename = "resetDate")]
     pub reset_date: u64,
-    pub created_by: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub created_by: Option<Option<String>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -67,6 +140,8 @@ pub struct SacramentAttendance {
     #[serde(rename = "personId")]
     pub person_id: String,
     pub date: String, // ISO 8601 format
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -78,73 +153,160 @@ pub struct Contact {
     pub household_id: String, // UUID as a String
 
     #[serde(rename = "status")]
-    pub status: u32,
-
-    #[serde(rename = "createdBy")]
-    pub created_by: Option<String>,
-
-    #[serde(rename = "first")]
-    pub first_name: Option<String>,
-
-    #[serde(rename = "last")]
-    pub last_name: Option<String>,
-
-    #[serde(rename = "gender")]
-    pub gender: Option<String>, // Assuming single-character string ("M"/"F")
-
-    #[serde(rename = "note")]
-    pub note: Option<String>,
-
-    #[serde(rename = "phoneMobile")]
-    pub phone_mobile: Option<String>, // Stored as a String to accommodate different phone formats
-
-    #[serde(rename = "phoneWork")]
-    pub phone_work: Option<String>,
-
-    #[serde(rename = "phoneMobileTextable")]
-    pub phone_mobile_textable: Option<bool>, // Boolean field
-
-    #[serde(rename = "ageCategory")]
-    pub age_category: Option<u32>,
+    pub status: ContactStatus,
+
+    #[serde(
+        rename = "createdBy",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub created_by: Option<Option<String>>,
+
+    #[serde(
+        rename = "first",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub first_name: Option<Option<String>>,
+
+    #[serde(
+        rename = "last",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub last_name: Option<Option<String>>,
+
+    #[serde(
+        rename = "gender",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub gender: Option<Option<String>>, // Assuming single-character string ("M"/"F")
+
+    #[serde(
+        rename = "note",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub note: Option<Option<String>>,
+
+    #[serde(
+        rename = "phoneMobile",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub phone_mobile: Option<Option<String>>, // Stored as a String to accommodate different phone formats
+
+    #[serde(
+        rename = "phoneWork",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub phone_work: Option<Option<String>>,
+
+    #[serde(
+        rename = "phoneMobileTextable",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub phone_mobile_textable: Option<Option<bool>>, // Boolean field
+
+    #[serde(
+        rename = "ageCategory",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub age_category: Option<Option<AgeCategory>>,
 
     #[serde(rename = "affirmedInterestExpirationDate")]
     pub affirmed_interest_expiration_date: u64, // Unix timestamp in milliseconds
 
-    #[serde(rename = "lastEventDate")]
-    pub last_event_date: Option<u64>, // Unix timestamp in milliseconds
-
-    #[serde(rename = "lastHappenedEventDate")]
-    pub last_happened_event_date: Option<u64>, // Unix timestamp in milliseconds
+    #[serde(
+        rename = "lastEventDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub last_event_date: Option<Option<u64>>, // Unix timestamp in milliseconds
+
+    #[serde(
+        rename = "lastHappenedEventDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub last_happened_event_date: Option<Option<u64>>, // Unix timestamp in milliseconds
 
     #[serde(rename = "contactSource")]
-    pub contact_source: u32,
+    pub contact_source: ContactSource,
 
-    #[serde(rename = "lastTaughtDate")]
-    pub last_taught_date: Option<u64>, // Unix timestamp in milliseconds
+    #[serde(
+        rename = "lastTaughtDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub last_taught_date: Option<Option<u64>>, // Unix timestamp in milliseconds
 
     #[serde(rename = "createDate")]
     pub create_date: u64, // Unix timestamp in milliseconds
 
-    #[serde(rename = "preferredLanguageId")]
-    pub preferred_language_id: Option<u16>,
+    #[serde(
+        rename = "preferredLanguageId",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub preferred_language_id: Option<Option<u16>>,
 
     #[serde(rename = "visibilityTypeId")]
-    pub visibility_type_id: u32,
+    pub visibility_type_id: VisibilityType,
 
     #[serde(rename = "ownerStatus")]
-    pub owner_status: u32,
-
-    #[serde(rename = "prosAreaId")]
-    pub pros_area_id: Option<u32>,
-
-    #[serde(rename = "lastReassignedDate")]
-    pub last_reassigned_date: Option<i64>, // Unix timestamp in milliseconds
-
-    #[serde(rename = "foundByPersonId")]
-    pub found_by_person_id: Option<String>, // Assuming this is an ID stored as a string
-
-    #[serde(rename = "membershipCreationDate")]
-    pub membership_creation_date: Option<String>,
+    pub owner_status: OwnerStatus,
+
+    #[serde(
+        rename = "prosAreaId",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub pros_area_id: Option<Option<u32>>,
+
+    #[serde(
+        rename = "lastReassignedDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub last_reassigned_date: Option<Option<i64>>, // Unix timestamp in milliseconds
+
+    #[serde(
+        rename = "foundByPersonId",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub found_by_person_id: Option<Option<String>>, // Assuming this is an ID stored as a string
+
+    #[serde(
+        rename = "membershipCreationDate",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "::serde_with::rust::double_option"
+    )]
+    pub membership_creation_date: Option<Option<String>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -161,26 +323,599 @@ pub struct CommandsResponse {
     pub contacts: Vec<Contact>,
     #[serde(rename = "prosAreaName")]
     pub pros_area_name: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl CommandsResponse {
+    /// Applies a delta sync on top of this full state. Records with an id we
+    /// already have replace the old one, everything else is added.
+    pub fn merge(&mut self, delta: CommandsResponse) {
+        upsert(&mut self.person_events, delta.person_events, |x| &x.id);
+        upsert(&mut self.events, delta.events, |x| &x.id);
+        upsert(&mut self.person_drops, delta.person_drops, |x| &x.id);
+        upsert(&mut self.person_resets, delta.person_resets, |x| &x.id);
+        upsert(
+            &mut self.sacrament_attendance,
+            delta.sacrament_attendance,
+            |x| &x.id,
+        );
+        upsert(&mut self.contacts, delta.contacts, |x| &x.id);
+        if !delta.pros_area_name.is_empty() {
+            self.pros_area_name = delta.pros_area_name;
+        }
+        self.extra.extend(delta.extra);
+    }
+
+    /// Builds the timeline of everyone in the area who isn't a member.
+    /// Sacrament dates are days in `timezone`, the mission's.
+    pub fn into_people(self, timezone: Tz) -> Vec<Person> {
+        let mut people_map = HashMap::new();
+        // A lesson with a whole family links one event to everyone in it
+        let mut person_events: HashMap<&String, Vec<String>> = HashMap::new();
+        for x in &self.person_events {
+            let participants = person_events.entry(&x.event_id).or_default();
+            if !participants.contains(&x.person_id) {
+                participants.push(x.person_id.clone());
+            }
+        }
+
+        for people in self.contacts {
+            if people.status == ContactStatus::Member {
+                continue;
+            }
+
+            people_map.insert(
+                people.id.clone(),
+                Person {
+                    id: people.id,
+                    household_id: people.household_id,
+                    status: people.status,
+                    first_name: people.first_name.flatten(),
+                    last_name: people.last_name.flatten(),
+                    gender: people.gender.flatten(),
+                    age_category: people.age_category.flatten(),
+                    owner_status: people.owner_status,
+                    created: people.create_date,
+                    language: people.preferred_language_id.flatten(),
+                    area: self.pros_area_name.clone(),
+                    area_id: people.pros_area_id.flatten(),
+                    timeline: Vec::new(),
+                },
+            );
+        }
+
+        for c in self.events {
+            let Some(time) = c.start_time.flatten() else {
+                continue;
+            };
+            let Some(participants) = person_events.get(&c.id) else {
+                continue;
+            };
+            // Members were skipped above, so a member sitting in doesn't split the lesson
+            let participants = participants
+                .iter()
+                .filter(|id| people_map.contains_key(*id))
+                .cloned()
+                .collect::<Vec<String>>();
+            for person_id in &participants {
+                if let Some(p) = people_map.get_mut(person_id) {
+                    p.timeline.push(TimelineEntry {
+                        time,
+                        kind: TimelineEventKind::Event {
+                            event: c.clone(),
+                            participants: participants.clone(),
+                        },
+                    })
+                }
+            }
+        }
+
+        for c in self.person_drops {
+            if let Some(p) = people_map.get_mut(&c.person_id) {
+                p.timeline.push(TimelineEntry {
+                    time: c.drop_date,
+                    kind: TimelineEventKind::Drop(c),
+                })
+            }
+        }
+
+        for c in self.person_resets {
+            if let Some(p) = people_map.get_mut(&c.person_id) {
+                p.timeline.push(TimelineEntry {
+                    time: c.reset_date,
+                    kind: TimelineEventKind::Reset(c),
+                })
+            }
+        }
+
+        for c in self.sacrament_attendance {
+            let Some(time) = sacrament_time(&c.date, timezone) else {
+                continue;
+            };
+            if let Some(p) = people_map.get_mut(&c.person_id) {
+                p.timeline.push(TimelineEntry {
+                    time,
+                    kind: TimelineEventKind::Sacrament(c),
+                })
+            }
+        }
+
+        let mut people = people_map.into_values().collect::<Vec<Person>>();
+        people
+            .iter_mut()
+            .for_each(|x| x.timeline.sort_by_key(|a| a.time));
+
+        people
+    }
+}
+
+/// The start of a sacrament attendance date (`YYYY-MM-DD`) in the mission's
+/// timezone, in milliseconds. `None` if the date doesn't parse.
+fn sacrament_time(date: &str, timezone: Tz) -> Option<u64> {
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    let midnight = NaiveDateTime::new(day, NaiveTime::default());
+    // Some zones skip midnight when DST starts, often on a Sunday, so the day
+    // starts at the first quarter hour that exists
+    let local = (0..24 * 4)
+        .map(|quarter| midnight + chrono::Duration::minutes(15 * quarter))
+        .find_map(|time| timezone.from_local_datetime(&time).earliest())?;
+    Some(local.timestamp_millis() as u64)
+}
+
+/// Replaces items with a matching id and appends the rest.
+fn upsert<T>(existing: &mut Vec<T>, new: Vec<T>, id: fn(&T) -> &String) {
+    let mut index = existing
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (id(x).clone(), i))
+        .collect::<HashMap<String, usize>>();
+
+    for item in new {
+        match index.get(id(&item)) {
+            Some(&i) => existing[i] = item,
+            None => {
+                index.insert(id(&item).clone(), existing.len());
+                existing.push(item);
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TimelineEntry {
+    /// Unix timestamp in milliseconds
     pub time: u64,
-    pub details: String,
     pub kind: TimelineEventKind,
 }
 
+/// What happened, with everything Areabook knows about it.
 #[derive(Debug)]
 pub enum TimelineEventKind {
-    Event,
-    Drop,
-    Reset,
-    Sacrament,
+    /// Shows up on the timeline of everyone in `participants`
+    Event {
+        event: Event,
+        /// Person ids of everyone the event was with, members left out
+        participants: Vec<String>,
+    },
+    Drop(Drop),
+    Reset(Reset),
+    Sacrament(SacramentAttendance),
+}
+
+impl TimelineEntry {
+    /// What kind of record this is and its id, the same in every area.
+    fn key(&self) -> (&'static str, String) {
+        match &self.kind {
+            TimelineEventKind::Event { event, .. } => ("event", event.id.clone()),
+            TimelineEventKind::Drop(d) => ("drop", d.id.clone()),
+            TimelineEventKind::Reset(r) => ("reset", r.id.clone()),
+            TimelineEventKind::Sacrament(s) => ("sacrament", s.id.clone()),
+        }
+    }
+
+    pub fn event(&self) -> Option<&Event> {
+        match &self.kind {
+            TimelineEventKind::Event { event, .. } => Some(event),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an event with more than one person, like a family lesson.
+    pub fn is_group(&self) -> bool {
+        match &self.kind {
+            TimelineEventKind::Event { participants, .. } => participants.len() > 1,
+            _ => false,
+        }
+    }
+
+    /// How much of the event belongs to the people in `ids`: 1/3 for one
+    /// person in a lesson with three, 2/3 for two of them. Anything that isn't
+    /// an event is 1.
+    pub fn share(&self, ids: &[&str]) -> f64 {
+        match &self.kind {
+            TimelineEventKind::Event { participants, .. } => {
+                let present = participants
+                    .iter()
+                    .filter(|p| ids.contains(&p.as_str()))
+                    .count();
+                present as f64 / participants.len().max(1) as f64
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+impl Contact {
+    /// "First Last", or the id if there's no name at all.
+    pub fn display_name(&self) -> String {
+        display_name(
+            self.first_name.as_ref().and_then(Option::as_deref),
+            self.last_name.as_ref().and_then(Option::as_deref),
+            &self.id,
+        )
+    }
 }
 
+/// Someone being taught, with everything that happened with them.
 pub struct Person {
-    pub name: String,
+    /// The contact id, the same in every area and snapshot
+    pub id: String,
+    pub household_id: String,
+    pub status: ContactStatus,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub gender: Option<String>,
+    pub age_category: Option<AgeCategory>,
+    /// When the contact was made, Unix timestamp in milliseconds
+    pub created: u64,
     pub language: Option<u16>,
+    /// Name of the area this was fetched from
     pub area: String,
+    /// The area that owns the contact, which can differ from `area`
+    pub area_id: Option<u32>,
+    /// Whether the area this was fetched from owns the contact
+    pub owner_status: OwnerStatus,
     pub timeline: Vec<TimelineEntry>,
 }
+
+impl Person {
+    /// "First Last", or the id if there's no name at all.
+    pub fn display_name(&self) -> String {
+        display_name(
+            self.first_name.as_deref(),
+            self.last_name.as_deref(),
+            &self.id,
+        )
+    }
+
+    /// Adds whatever another area's copy of the same person has that this one
+    /// doesn't.
+    pub fn merge(&mut self, other: Person) {
+        let mut have = self
+            .timeline
+            .iter()
+            .map(TimelineEntry::key)
+            .collect::<HashSet<(&'static str, String)>>();
+        for entry in other.timeline {
+            if have.insert(entry.key()) {
+                self.timeline.push(entry);
+            }
+        }
+        self.timeline.sort_by_key(|e| e.time);
+    }
+}
+
+fn display_name(first: Option<&str>, last: Option<&str>, id: &str) -> String {
+    let name = [first, last]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if name.is_empty() {
+        id.to_string()
+    } else {
+        name
+    }
+}
+
+/// Areabook JSON for tests, with everything required filled in so each test
+/// only spells out what it's about.
+#[cfg(test)]
+pub(crate) mod test_data {
+    use serde_json::{json, Value};
+
+    use super::CommandsResponse;
+
+    /// Someone being taught, named after their id, in a household of their own.
+    pub fn contact(id: &str) -> Value {
+        json!({
+            "id": id,
+            "householdId": id,
+            "status": 10,
+            "first": id,
+            "affirmedInterestExpirationDate": 0,
+            "contactSource": 1,
+            "createDate": 0,
+            "visibilityTypeId": 1,
+            "ownerStatus": 1,
+        })
+    }
+
+    /// A lesson at `start_time`, in milliseconds.
+    pub fn event(id: &str, start_time: u64) -> Value {
+        json!({
+            "id": id,
+            "startTime": start_time,
+            "eventType": 1,
+            "lessonYN": 1,
+            "ownerYN": 1,
+            "memberPresentYN": 0,
+        })
+    }
+
+    /// `base` with each of `fields` set on it.
+    pub fn with(mut base: Value, fields: Value) -> Value {
+        if let (Value::Object(base), Value::Object(fields)) = (&mut base, fields) {
+            base.extend(fields);
+        }
+        base
+    }
+
+    /// "Test Area" with nothing in it but `fields`.
+    pub fn commands(fields: Value) -> CommandsResponse {
+        let empty = json!({
+            "personEvents": [],
+            "events": [],
+            "personDrops": [],
+            "personResets": [],
+            "sacramentAttendance": [],
+            "contacts": [],
+            "prosAreaName": "Test Area",
+        });
+        serde_json::from_value(with(empty, fields)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_data::{commands, contact, event, with};
+    use super::*;
+    use serde_json::json;
+
+    /// p1 and p2, p1 reset once, with `sacrament` for attendance.
+    fn area(sacrament: Value) -> CommandsResponse {
+        commands(json!({
+            "personResets": [
+                { "id": "r1", "personId": "p1", "resetDate": 1_700_000_000_000u64 }
+            ],
+            "sacramentAttendance": sacrament,
+            "contacts": [contact("p1"), contact("p2")],
+        }))
+    }
+
+    fn person<'a>(people: &'a [Person], id: &str) -> &'a Person {
+        people.iter().find(|p| p.id == id).unwrap()
+    }
+
+    #[test]
+    fn sacrament_attaches_by_person_id() {
+        let people = area(json!([
+            { "id": "s1", "personId": "p2", "date": "2024-03-03" }
+        ]))
+        .into_people(Tz::UTC);
+
+        let p2 = person(&people, "p2");
+        assert_eq!(p2.timeline.len(), 1);
+        assert!(matches!(
+            p2.timeline[0].kind,
+            TimelineEventKind::Sacrament(_)
+        ));
+        assert_eq!(p2.timeline[0].time, 1_709_424_000_000);
+        assert!(person(&people, "p1")
+            .timeline
+            .iter()
+            .all(|e| !matches!(e.kind, TimelineEventKind::Sacrament(_))));
+    }
+
+    #[test]
+    fn sacrament_is_midnight_in_the_mission_timezone() {
+        let people = area(json!([
+            { "id": "s1", "personId": "p1", "date": "2024-03-03" }
+        ]))
+        .into_people(chrono_tz::America::Denver);
+
+        let sacrament = person(&people, "p1")
+            .timeline
+            .iter()
+            .find(|e| matches!(e.kind, TimelineEventKind::Sacrament(_)))
+            .unwrap();
+        // Denver is UTC-7 in March before DST starts
+        assert_eq!(sacrament.time, 1_709_424_000_000 + 7 * 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn resets_are_left_alone() {
+        let people = area(json!([
+            { "id": "s1", "personId": "p1", "date": "2024-03-03" }
+        ]))
+        .into_people(Tz::UTC);
+
+        let resets = person(&people, "p1")
+            .timeline
+            .iter()
+            .filter(|e| matches!(e.kind, TimelineEventKind::Reset(_)))
+            .map(|e| e.time)
+            .collect::<Vec<u64>>();
+        assert_eq!(resets, vec![1_700_000_000_000]);
+    }
+
+    #[test]
+    fn group_events_reach_every_participant() {
+        let mut res = area(json!([]));
+        res.events
+            .push(serde_json::from_value(event("e1", 1_700_000_000_000)).unwrap());
+        for (id, person_id) in [("pe1", "p1"), ("pe2", "p2")] {
+            res.person_events.push(
+                serde_json::from_value(json!({ "id": id, "personId": person_id, "eventId": "e1" }))
+                    .unwrap(),
+            );
+        }
+        let people = res.into_people(Tz::UTC);
+
+        for name in ["p1", "p2"] {
+            let entry = person(&people, name)
+                .timeline
+                .iter()
+                .find(|e| e.event().is_some())
+                .unwrap();
+            assert!(entry.is_group());
+            assert_eq!(entry.share(&[name]), 0.5);
+            assert_eq!(entry.share(&["p1", "p2"]), 1.0);
+        }
+    }
+
+    #[test]
+    fn members_present_dont_count_as_participants() {
+        let mut res = area(json!([]));
+        let member = with(contact("m1"), json!({ "status": 40 }));
+        res.contacts.push(serde_json::from_value(member).unwrap());
+        let lesson = with(
+            event("e1", 1_700_000_000_000),
+            json!({ "memberPresentYN": 1 }),
+        );
+        res.events.push(serde_json::from_value(lesson).unwrap());
+        for (id, person_id) in [("pe1", "p1"), ("pe2", "m1")] {
+            res.person_events.push(
+                serde_json::from_value(json!({ "id": id, "personId": person_id, "eventId": "e1" }))
+                    .unwrap(),
+            );
+        }
+        let people = res.into_people(Tz::UTC);
+
+        assert!(people.iter().all(|p| p.id != "m1"));
+        let entry = person(&people, "p1")
+            .timeline
+            .iter()
+            .find(|e| e.event().is_some())
+            .unwrap();
+        assert!(!entry.is_group());
+        assert_eq!(entry.share(&["p1"]), 1.0);
+    }
+
+    #[test]
+    fn unknown_people_and_bad_dates_are_skipped() {
+        let people = area(json!([
+            { "id": "s1", "personId": "nobody", "date": "2024-03-03" },
+            { "id": "s2", "personId": "p2", "date": "last sunday" }
+        ]))
+        .into_people(Tz::UTC);
+
+        assert!(person(&people, "p2").timeline.is_empty());
+    }
+
+    #[test]
+    fn commands_round_trip_with_unknown_fields() {
+        let mut original: Value =
+            serde_json::from_str(include_str!("../fixtures/commands/30010.json")).unwrap();
+        original["syncToken"] = json!({ "next": "abc", "pages": [1, null] });
+        original["contacts"][0]["favoriteColor"] = json!("blue");
+        original["events"][0]["location"] = json!({ "lat": 40.2, "lng": null });
+        original["personDrops"][0]["reasonCode"] = json!(7);
+        // Absent has to stay absent and null has to stay null
+        original["events"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("modBy");
+        original["events"][1]["modBy"] = Value::Null;
+
+        let parsed: CommandsResponse = serde_json::from_value(original.clone()).unwrap();
+        assert!(parsed.contacts[0].extra.contains_key("favoriteColor"));
+        assert_eq!(parsed.events[0].modified_by, None);
+        assert_eq!(parsed.events[1].modified_by, Some(None));
+        let written = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(written, original);
+    }
+
+    #[test]
+    fn display_name_joins_first_and_last() {
+        let names = [
+            (json!("John"), json!("Smith"), "John Smith"),
+            (json!("John"), Value::Null, "John"),
+            (Value::Null, json!("Smith"), "Smith"),
+            (json!("  John "), json!(" Smith"), "John Smith"),
+            (json!("   "), json!("Smith"), "Smith"),
+            (json!(" "), json!("\t"), "p5"),
+            (Value::Null, Value::Null, "p6"),
+        ];
+        let contacts = names
+            .iter()
+            .enumerate()
+            .map(|(i, (first, last, _))| {
+                with(
+                    contact(&format!("p{i}")),
+                    json!({ "first": first, "last": last }),
+                )
+            })
+            .collect::<Vec<Value>>();
+        let res = commands(json!({ "contacts": contacts }));
+        let contacts = res
+            .contacts
+            .iter()
+            .map(Contact::display_name)
+            .collect::<Vec<String>>();
+        let people = res.into_people(Tz::UTC);
+
+        for (i, (_, _, expected)) in names.iter().enumerate() {
+            assert_eq!(contacts[i], *expected);
+            assert_eq!(person(&people, &format!("p{i}")).display_name(), *expected);
+        }
+    }
+
+    #[test]
+    fn merge_replaces_by_id_and_adds_the_rest() {
+        let mut full = area(json!([
+            { "id": "s1", "personId": "p1", "date": "2024-03-03" }
+        ]));
+        full.extra.insert("kept".to_string(), json!(1));
+        let delta = commands(json!({
+            "personResets": [
+                { "id": "r1", "personId": "p1", "resetDate": 1_800_000_000_000u64 },
+                { "id": "r2", "personId": "p2", "resetDate": 1_800_000_000_000u64 }
+            ],
+            "contacts": [with(contact("p1"), json!({ "first": "Renamed" })), contact("p3")],
+            "prosAreaName": "",
+            "added": true,
+        }));
+        full.merge(delta);
+
+        let ids = |contacts: &[Contact]| contacts.iter().map(|c| c.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&full.contacts), vec!["p1", "p2", "p3"]);
+        assert_eq!(full.contacts[0].display_name(), "Renamed");
+        assert_eq!(full.person_resets.len(), 2);
+        assert_eq!(full.person_resets[0].reset_date, 1_800_000_000_000);
+        assert_eq!(full.sacrament_attendance.len(), 1);
+        // An empty name in the delta doesn't wipe the area's
+        assert_eq!(full.pros_area_name, "Test Area");
+        assert!(full.extra.contains_key("kept") && full.extra.contains_key("added"));
+    }
+
+    #[test]
+    fn sacrament_on_a_day_without_midnight() {
+        // Santiago skipped from midnight to 01:00 on this Sunday, which is
+        // 04:00 UTC either way
+        let time = sacrament_time("2024-09-08", chrono_tz::America::Santiago);
+        assert_eq!(time, Some(1_725_768_000_000));
+        for (date, timezone) in [
+            ("2024-10-06", chrono_tz::America::Asuncion),
+            ("2024-03-10", chrono_tz::America::Havana),
+        ] {
+            assert!(
+                sacrament_time(date, timezone).is_some(),
+                "{date} in {timezone}"
+            );
+        }
+    }
+}